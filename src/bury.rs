@@ -0,0 +1,521 @@
+use filetime::FileTime;
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::{CString, OsString},
+    fs,
+    io::{self, Write},
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{lchown, FileTypeExt, MetadataExt, PermissionsExt},
+        net::UnixListener,
+    },
+    path::{Path, PathBuf},
+};
+use walkdir::{WalkDir, WalkDirIterator};
+
+use crate::checksum;
+use crate::copy::copy_contents;
+use crate::errors::*;
+use crate::graveyard::Dropped;
+use crate::interrupt;
+use crate::util::walk;
+
+/// Extended attributes that POSIX ACLs are stored in
+const ACL_XATTRS: [&str; 2] = ["system.posix_acl_access", "system.posix_acl_default"];
+
+/// How a file got to its destination
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Moved {
+    /// It was renamed, so there is nothing left at the source
+    Renamed,
+    /// It was copied, and the source still has to be removed
+    Copied,
+}
+
+/// What was left out of a copy that is still good enough to keep
+#[derive(Debug, Default)]
+struct Report {
+    /// Files that weren't copied as they are, e.g., device nodes replaced by
+    /// markers, which can't be compared with their copy
+    inexact: HashSet<PathBuf>,
+    /// Device nodes that couldn't be made, and weren't allowed to be replaced
+    /// by markers
+    devices: Vec<PathBuf>,
+    /// Extended attributes that couldn't be carried over
+    dropped: Vec<Dropped>,
+}
+
+/// Move `source` to `dest`, copying it if they are not on the same filesystem.
+/// If anything couldn't be copied, the partial copy is removed. With
+/// `checksum`, the contents and modes of the copy must match the source too.
+/// Device nodes that can't be made at `dest` are replaced by `markers` if
+/// allowed, and fail with [`ErrorKind::NoDevice`] otherwise. Returns how the
+/// file got there, along with the extended attributes that were dropped.
+pub(crate) fn transfer<S, D>(source: S, dest: D, markers: bool, checksum: bool) -> Result<(Moved, Vec<Dropped>)>
+where
+    S: AsRef<Path>,
+    D: AsRef<Path>,
+{
+    let (source, dest) = (source.as_ref(), dest.as_ref());
+    // The parent is made first, so that a missing one isn't mistaken for
+    // being on another filesystem
+    let parent = dest.parent().ok_or("Couldn't get parent of dest")?;
+    fs::create_dir_all(parent).chain_err(|| "Couldn't create parent dir")?;
+
+    // Try a simple rename, which will only work within the same mount point.
+    // Trying to rename across filesystems will throw errno 18.
+    if fs::rename(source, dest).is_ok() {
+        return Ok((Moved::Renamed, Vec::new()));
+    }
+
+    // If that didn't work, then copy and rm.
+
+    let is_dir = fs::symlink_metadata(source)
+        .chain_err(|| "Couldn't get metadata")?
+        .is_dir();
+
+    let mut report = Report::default();
+    let mut problems = if is_dir {
+        copy_tree(source, dest, markers, &mut report)
+    } else {
+        match copy_file(source, dest, markers, &mut report) {
+            Ok(()) => Vec::new(),
+            Err(e) => vec![format!("{}: {}", source.display(), e)],
+        }
+    };
+    if problems.is_empty() && report.devices.is_empty() && !interrupt::requested() {
+        problems = verify_copy(source, dest, &report.inexact, checksum);
+    }
+
+    if interrupt::requested() {
+        remove(dest).ok();
+        bail!(ErrorKind::Interrupted(source.to_path_buf()));
+    }
+    if !problems.is_empty() {
+        // Leave the source as it was and get rid of the partial copy
+        remove(dest).ok();
+        bail!(ErrorKind::IncompleteCopy(source.to_path_buf(), problems));
+    }
+    if !report.devices.is_empty() {
+        remove(dest).ok();
+        bail!(ErrorKind::NoDevice(source.to_path_buf(), report.devices));
+    }
+
+    Ok((Moved::Copied, report.dropped))
+}
+
+/// Remove `source` once it has been copied to `dest`. If a file can't be
+/// removed, its copy is removed instead. A directory may have been partly
+/// removed, so its copy is kept.
+pub(crate) fn remove_source(source: &Path, dest: &Path) -> Result<()> {
+    if fs::symlink_metadata(source).is_ok_and(|m| m.is_dir()) {
+        // Part of the directory may already be gone, so the copy has to stay
+        fs::remove_dir_all(source)
+            .chain_err(|| ErrorKind::PartialRemoval(source.to_path_buf()))?;
+    } else if let Err(e) = fs::remove_file(source) {
+        remove(dest).ok();
+        return Err(e).chain_err(|| format!("Failed to remove file: {}", source.display()));
+    }
+
+    Ok(())
+}
+
+/// Put a file moved by [`transfer`] back where it was
+pub(crate) fn undo(source: &Path, dest: &Path, moved: Moved) -> io::Result<()> {
+    match moved {
+        Moved::Renamed => fs::rename(dest, source),
+        Moved::Copied => remove(dest),
+    }
+}
+
+/// Copy the directory `source` to `dest`, adding what was left out of the copy
+/// to `report`. Returns everything that went wrong.
+fn copy_tree(source: &Path, dest: &Path, markers: bool, report: &mut Report) -> Vec<String> {
+    let mut problems: Vec<String> = Vec::new();
+    // Directories are given their metadata once everything inside of them
+    // has been copied, since copying into them changes their mtime and
+    // their mode could keep us from writing to them
+    let mut dirs: Vec<(PathBuf, PathBuf, fs::Metadata)> = Vec::new();
+    // Where the first copy of each inode with more than one link went, so
+    // that other links to it inside the tree are linked to that copy
+    let mut links: HashMap<(u64, u64), PathBuf> = HashMap::new();
+
+    // Walk the source, creating directories and copying files as needed
+    let mut walker = WalkDir::new(source).into_iter();
+    while let Some(entry) = walker.next() {
+        if interrupt::requested() {
+            return problems;
+        }
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                problems.push(e.to_string());
+                continue;
+            },
+        };
+        // Path without the top-level directory
+        let orphan: &Path = match entry.path().strip_prefix(source) {
+            Ok(orphan) => orphan,
+            Err(_) => {
+                problems.push(format!(
+                    "{}: parent directory isn't a prefix of child directories",
+                    entry.path().display()
+                ));
+                continue;
+            },
+        };
+        let metadata = match entry.metadata() {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                problems.push(e.to_string());
+                continue;
+            },
+        };
+
+        if entry.file_type().is_dir() {
+            if let Err(e) = fs::create_dir_all(dest.join(orphan)) {
+                problems.push(format!(
+                    "Failed to create {} in {}: {}",
+                    entry.path().display(),
+                    dest.join(orphan).display(),
+                    e
+                ));
+                walker.skip_current_dir();
+                continue;
+            }
+            dirs.push((entry.path().to_path_buf(), dest.join(orphan), metadata));
+            continue;
+        }
+
+        if metadata.nlink() > 1 {
+            let inode = (metadata.dev(), metadata.ino());
+            if let Some(first) = links.get(&inode) {
+                if let Err(e) = fs::hard_link(first, dest.join(orphan)) {
+                    problems.push(format!(
+                        "Failed to link {} to {}: {}",
+                        dest.join(orphan).display(),
+                        first.display(),
+                        e
+                    ));
+                }
+                continue;
+            }
+            links.insert(inode, dest.join(orphan));
+        }
+
+        if let Err(e) = copy_file(entry.path(), dest.join(orphan), markers, report) {
+            problems.push(format!(
+                "Failed to copy file from {} to {}: {}",
+                entry.path().display(),
+                dest.join(orphan).display(),
+                e
+            ));
+        }
+    }
+
+    // Deepest directories come last in the walk
+    for (orig, dir, metadata) in dirs.iter().rev() {
+        if let Err(e) = copy_metadata(orig, dir, metadata, &mut report.dropped) {
+            problems.push(format!("Failed to copy metadata to {}: {}", dir.display(), e));
+        }
+    }
+
+    problems
+}
+
+/// Check that everything in `source`, other than the files in `inexact`, made
+/// it to `dest` as the same type of file with the same size. With `checksum`,
+/// the contents of regular files and the modes must be the same as well.
+/// Returns every difference that was found.
+fn verify_copy(
+    source: &Path,
+    dest: &Path,
+    inexact: &HashSet<PathBuf>,
+    checksum: bool,
+) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    for entry in walk(source) {
+        if interrupt::requested() {
+            break;
+        }
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                problems.push(format!("{}: {}", source.display(), e));
+                continue;
+            },
+        };
+        if inexact.contains(&entry.path) {
+            continue;
+        }
+        let copy = match entry.path.strip_prefix(source) {
+            Ok(orphan) if orphan.as_os_str().is_empty() => dest.to_path_buf(),
+            Ok(orphan) => dest.join(orphan),
+            Err(_) => continue,
+        };
+
+        match fs::symlink_metadata(&copy) {
+            Err(e) => problems.push(format!("{}: {}", copy.display(), e)),
+            Ok(copied) => {
+                let orig = &entry.metadata;
+                if orig.file_type() != copied.file_type() {
+                    problems.push(format!("{}: wrong type of file", copy.display()));
+                } else if orig.is_file() && orig.len() != copied.len() {
+                    problems.push(format!(
+                        "{}: expected {} bytes, found {}",
+                        copy.display(),
+                        orig.len(),
+                        copied.len()
+                    ));
+                } else if checksum && orig.permissions().mode() != copied.permissions().mode() {
+                    problems.push(format!(
+                        "{}: expected mode {:o}, found {:o}",
+                        copy.display(),
+                        orig.permissions().mode() & 0o7777,
+                        copied.permissions().mode() & 0o7777
+                    ));
+                } else if checksum && orig.is_file() {
+                    match checksum::same_contents(&entry.path, &copy) {
+                        Ok(true) => {},
+                        Ok(false) => problems.push(format!(
+                            "{}: contents don't match the source",
+                            copy.display()
+                        )),
+                        Err(e) => problems.push(format!("{}: {}", copy.display(), e)),
+                    }
+                }
+            },
+        }
+    }
+
+    problems
+}
+
+/// Remove a file or directory, whichever `path` is
+pub(crate) fn remove(path: &Path) -> io::Result<()> {
+    if fs::symlink_metadata(path)?.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    }
+}
+
+/// Copy a single file that isn't a directory, adding what was left out of the
+/// copy to `report`
+fn copy_file<S, D>(source: S, dest: D, markers: bool, report: &mut Report) -> io::Result<()>
+where
+    S: AsRef<Path>,
+    D: AsRef<Path>,
+{
+    let (source, dest) = (source.as_ref(), dest.as_ref());
+    let metadata = fs::symlink_metadata(source)?;
+    let filetype = metadata.file_type();
+
+    if filetype.is_file() {
+        copy_contents(source, dest)?;
+    } else if filetype.is_symlink() {
+        let target = fs::read_link(source)?;
+        std::os::unix::fs::symlink(target, dest)?;
+    } else if filetype.is_fifo() {
+        mknod(dest, libc::S_IFIFO, &metadata)?;
+    } else if filetype.is_socket() {
+        // A socket has nothing to copy but its metadata. If the node can't be
+        // made, an empty file stands in for it.
+        if mknod(dest, libc::S_IFSOCK, &metadata).is_err() {
+            fs::File::create(dest)?;
+            copy_metadata(source, dest, &metadata, &mut report.dropped)?;
+            report.inexact.insert(source.to_path_buf());
+            return Ok(());
+        }
+    } else if mknod(
+        dest,
+        if filetype.is_char_device() { libc::S_IFCHR } else { libc::S_IFBLK },
+        &metadata,
+    )
+    .is_err()
+    {
+        // Device nodes can only be made when running privileged
+        if !markers {
+            report.devices.push(source.to_path_buf());
+            return Ok(());
+        }
+        // Create a dummy file to act as a marker in the graveyard
+        let mut marker = fs::File::create(dest)?;
+        marker.write_all(
+            b"This is a marker for a file that was \
+                           permanently deleted.  Requiescat in pace.",
+        )?;
+        report.inexact.insert(source.to_path_buf());
+        return Ok(());
+    }
+
+    copy_metadata(source, dest, &metadata, &mut report.dropped)
+}
+
+/// Give `dest` the ownership, extended attributes, mode and timestamps of
+/// `source`, which are described by `metadata`. The owner is only changed
+/// when permitted, i.e., when running as root or when only the group changes
+/// to one the user is in. Attributes that couldn't be set are added to
+/// `dropped`.
+fn copy_metadata(
+    source: &Path,
+    dest: &Path,
+    metadata: &fs::Metadata,
+    dropped: &mut Vec<Dropped>,
+) -> io::Result<()> {
+    // Changing the owner clears the setuid and setgid bits, so do it first
+    set_owner(dest, metadata)?;
+
+    // Set before the mode, which could keep us from writing them
+    dropped.extend(copy_xattrs(source, dest).into_iter().map(|(name, e)| Dropped {
+        path: dest.to_path_buf(),
+        name,
+        reason: e.to_string(),
+    }));
+
+    set_mode_and_times(dest, metadata)
+}
+
+/// Give `dest` the owner of `metadata`, or at least its group if not permitted
+fn set_owner(dest: &Path, metadata: &fs::Metadata) -> io::Result<()> {
+    if let Err(e) = lchown(dest, Some(metadata.uid()), Some(metadata.gid())) {
+        if e.kind() != io::ErrorKind::PermissionDenied {
+            return Err(e);
+        }
+        lchown(dest, None, Some(metadata.gid())).ok();
+    }
+    Ok(())
+}
+
+fn set_mode_and_times(dest: &Path, metadata: &fs::Metadata) -> io::Result<()> {
+    // Symlinks don't have a mode of their own on Linux
+    if !metadata.file_type().is_symlink() {
+        fs::set_permissions(dest, fs::Permissions::from_mode(metadata.mode() & 0o7777))?;
+    }
+
+    filetime::set_symlink_file_times(
+        dest,
+        FileTime::from_last_access_time(metadata),
+        FileTime::from_last_modification_time(metadata),
+    )
+}
+
+/// Copy the extended attributes of `source` to `dest`, which includes POSIX
+/// ACLs as they are stored as `system.posix_acl_*` attributes. Attributes in
+/// namespaces like `security` or `trusted` are only carried over if permitted.
+/// Returns the attributes that couldn't be copied, along with the reason why.
+fn copy_xattrs(source: &Path, dest: &Path) -> Vec<(OsString, io::Error)> {
+    let mut failed = Vec::new();
+
+    let mut names: Vec<OsString> = match xattr::list(source) {
+        Ok(names) => names.collect(),
+        // Nothing to carry over from a filesystem without extended attributes
+        Err(e) if e.kind() == io::ErrorKind::Unsupported => return failed,
+        Err(e) => {
+            failed.push((OsString::from("extended attributes"), e));
+            return failed;
+        },
+    };
+    // Some filesystems don't list ACLs
+    for acl in ACL_XATTRS {
+        if !names.iter().any(|name| name == acl) {
+            names.push(OsString::from(acl));
+        }
+    }
+
+    for name in names {
+        match xattr::get(source, &name) {
+            Ok(Some(value)) => {
+                if let Err(e) = xattr::set(dest, &name, &value) {
+                    failed.push((name, e));
+                }
+            },
+            // Files like symlinks can't have ACLs
+            Ok(None) => {},
+            Err(e) if e.kind() == io::ErrorKind::Unsupported => {},
+            Err(e) => failed.push((name, e)),
+        }
+    }
+
+    failed
+}
+
+/// Make a special file at `path` of type `kind` (one of the `libc::S_IF*`
+/// constants), with the mode and device number described by `metadata`
+fn mknod(path: &Path, kind: libc::mode_t, metadata: &fs::Metadata) -> io::Result<()> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let mode = kind | (metadata.mode() & 0o7777) as libc::mode_t;
+    if unsafe { libc::mknod(path.as_ptr(), mode, metadata.rdev() as libc::dev_t) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Turn the empty file standing in for a socket at `path` back into a socket
+pub(crate) fn restore_socket(path: &Path) -> io::Result<()> {
+    let metadata = fs::symlink_metadata(path)?;
+    fs::remove_file(path)?;
+    // Binding makes the socket node, which is left behind once the listener is
+    // dropped. It is as good as the original once the process that created
+    // that one has exited.
+    UnixListener::bind(path)?;
+    set_owner(path, &metadata)?;
+    set_mode_and_times(path, &metadata)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, os::unix::fs::symlink, process};
+
+    /// A directory of its own on each of two filesystems, which are removed
+    /// when dropped
+    struct Scratch {
+        near: PathBuf,
+        far: PathBuf,
+    }
+
+    impl Scratch {
+        /// `None` if there is no tmpfs at /dev/shm on another filesystem
+        fn new(name: &str) -> Option<Self> {
+            let dir = format!("rip-bury-{}-{}", name, process::id());
+            let (near, far) = (Path::new("/dev/shm").join(&dir), env::temp_dir().join(&dir));
+            fs::create_dir_all(&near).ok()?;
+            fs::create_dir_all(&far).ok()?;
+            let scratch = Scratch { near, far };
+            let dev = |p: &Path| fs::metadata(p).map(|m| m.dev()).ok();
+            if dev(&scratch.near) == dev(&scratch.far) {
+                return None;
+            }
+            Some(scratch)
+        }
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            fs::remove_dir_all(&self.near).ok();
+            fs::remove_dir_all(&self.far).ok();
+        }
+    }
+
+    #[test]
+    fn copies_symlinks_across_filesystems() {
+        let scratch = match Scratch::new("symlink") {
+            Some(scratch) => scratch,
+            None => return,
+        };
+        let dir = scratch.near.join("target");
+        fs::create_dir(&dir).unwrap();
+        fs::write(dir.join("file"), "contents").unwrap();
+        for (name, target) in [("dir", dir), ("dangling", scratch.near.join("nonexistent"))] {
+            let source = scratch.near.join(name);
+            let dest = scratch.far.join(name);
+            symlink(&target, &source).unwrap();
+
+            let (moved, _) = transfer(&source, &dest, false, true).unwrap();
+            assert_eq!(moved, Moved::Copied);
+            assert_eq!(fs::read_link(&dest).unwrap(), target);
+        }
+    }
+}
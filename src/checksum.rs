@@ -0,0 +1,81 @@
+//! Checksums of graves, used to make sure that a copy across filesystems
+//! matches its source, and to find graves that changed after being buried.
+//!
+//! The digest of a grave covers its whole tree: the path, type and permission
+//! bits of each entry, along with the contents of regular files and the
+//! targets of symlinks. Digests are written as `sha256:<hex>`.
+use sha2::{Digest, Sha256};
+use std::{
+    fs,
+    io::{self, Read},
+    os::unix::{ffi::OsStrExt, fs::PermissionsExt},
+    path::Path,
+};
+
+use crate::{interrupt, util::walk};
+
+/// Name of the algorithm, which prefixes each digest
+const ALGORITHM: &str = "sha256";
+
+/// Digest of the file or directory at `path`
+pub fn digest<P: AsRef<Path>>(path: P) -> io::Result<String> {
+    let path = path.as_ref();
+    let mut hasher = Sha256::new();
+
+    for entry in walk(path) {
+        let entry = entry?;
+        let relative = entry.path.strip_prefix(path).unwrap_or(&entry.path);
+        let metadata = &entry.metadata;
+        let kind = metadata.file_type();
+
+        // Lengths are included so that one entry can't run into the next
+        hasher.update((relative.as_os_str().len() as u64).to_le_bytes());
+        hasher.update(relative.as_os_str().as_bytes());
+        hasher.update(kind_tag(metadata).to_le_bytes());
+        if kind.is_file() {
+            hasher.update(metadata.len().to_le_bytes());
+            hash_contents(&mut hasher, &entry.path)?;
+        } else if kind.is_symlink() {
+            let target = fs::read_link(&entry.path)?;
+            hasher.update((target.as_os_str().len() as u64).to_le_bytes());
+            hasher.update(target.as_os_str().as_bytes());
+        }
+    }
+
+    Ok(format!("{}:{}", ALGORITHM, hex(&hasher.finalize())))
+}
+
+/// Whether the regular files `a` and `b` have the same contents
+pub(crate) fn same_contents(a: &Path, b: &Path) -> io::Result<bool> {
+    let (mut first, mut second) = (Sha256::new(), Sha256::new());
+    hash_contents(&mut first, a)?;
+    hash_contents(&mut second, b)?;
+    Ok(first.finalize() == second.finalize())
+}
+
+fn hash_contents(hasher: &mut Sha256, path: &Path) -> io::Result<()> {
+    let mut f = fs::File::open(path)?;
+    let mut buf = vec![0; 1 << 16];
+    loop {
+        interrupt::check()?;
+        match f.read(&mut buf)? {
+            0 => return Ok(()),
+            n => hasher.update(&buf[..n]),
+        }
+    }
+}
+
+/// The file type and permission bits of an entry. Symlinks have no mode of
+/// their own, so only their type is used.
+fn kind_tag(metadata: &fs::Metadata) -> u32 {
+    let mode = metadata.permissions().mode();
+    if metadata.file_type().is_symlink() {
+        mode & libc::S_IFMT
+    } else {
+        mode & (libc::S_IFMT | 0o7777)
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
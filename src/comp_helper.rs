@@ -1,65 +1,34 @@
 pub const ZSH_COMPLETION_REP: &[(&str, &str)] = &[
     (
-        r#"'*::TARGET -- File or directory to remove:' \
-":: :_rip_commands" \
-"*::: :->rip" \"#,
+        r#"'*::TARGET -- File or directory to remove:' \"#,
         r#"'*::TARGET -- File or directory to remove:_files' \"#,
     ),
     (
-        r#"    case $state in
-    (rip)
-        words=($line[2] "${words[@]}")
-        (( CURRENT += 1 ))
-        curcontext="${curcontext%:*:*}:rip-command-$line[2]:"
-        case $line[2] in
-            (completions)
-_arguments "${_arguments_options[@]}" \
-'-s+[Selects shell]: :(bash elvish fish powershell zsh)' \
-'--shell=[Selects shell]: :(bash elvish fish powershell zsh)' \
-'-h[Prints help information]' \
-'--help[Prints help information]' \
-'-V[Prints version information]' \
-'--version[Prints version information]' \
-&& ret=0
-;;
-(help)
-_arguments "${_arguments_options[@]}" \
-'-h[Prints help information]' \
-'--help[Prints help information]' \
-'-V[Prints version information]' \
-'--version[Prints version information]' \
-&& ret=0
-;;
-        esac
-    ;;
-esac"#,
-    r#""#,
+        r#""completions:AutoCompletion" \
+"#,
+        "",
     ),
     (
-        "(( $+functions[_rip_commands] )) ||
-_rip_commands() {
-    local commands; commands=(
-        \"completions:AutoCompletion\" \\
-\"help:Prints this message or the help of the given subcommand(s)\" \\
-    )
-    _describe -t commands 'rip commands' commands \"$@\"
-}
-(( $+functions[_rip__completions_commands] )) ||
-_rip__completions_commands() {
-    local commands; commands=(
-\x20\x20\x20\x20\x20\x20\x20\x20
-    )
-    _describe -t commands 'rip completions commands' commands \"$@\"
-}
-(( $+functions[_rip__help_commands] )) ||
-_rip__help_commands() {
-    local commands; commands=(
-\x20\x20\x20\x20\x20\x20\x20\x20
-    )
-    _describe -t commands 'rip help commands' commands \"$@\"
-}
+        r#"    _describe -t commands 'rip commands' commands "$@"
+"#,
+        r#"    _describe -t commands 'rip commands' commands "$@"
+    _files
+"#,
+    ),
+    (
+        "        esac\n    ;;\nesac",
+        "(*)\n_files && ret=0\n;;\n        esac\n    ;;\nesac",
+    ),
+];
 
-_rip \"$@\"",
-        r#"_rip "$@""#
-    )
+/// Parts of the Zsh completions that are removed whole, from the first string
+/// through the second. These hide the `completions` subcommand, which is only
+/// used to write this script.
+pub const ZSH_COMPLETION_SPANS: &[(&str, &str, &str)] = &[
+    ("(completions)\n", ";;\n", ""),
+    (
+        "(( $+functions[_rip__completions_commands] )) ||\n",
+        "\n}\n",
+        "",
+    ),
 ];
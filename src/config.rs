@@ -0,0 +1,151 @@
+//! Settings for `rip`, read from `$RIP_CONFIG` if set, else from
+//! `$XDG_CONFIG_HOME/rip/config.toml`, else from `~/.config/rip/config.toml`.
+//! Every setting is optional, and a missing file is the same as an empty one.
+//!
+//! ```toml
+//! [gc]
+//! # Expire graves that were buried longer ago than this
+//! older_than = "30d"
+//! # Expire the oldest graves while the graveyard takes more space than this
+//! max_size = "10G"
+//! # Collect garbage after each burial
+//! auto = true
+//!
+//! [unbury]
+//! # What to do when a file is unburied to a path that is taken: rename,
+//! # overwrite, skip, prompt or fail
+//! conflict = "prompt"
+//!
+//! [quota]
+//! # Refuse to bury anything that would make the graveyard bigger than this
+//! max_size = "20G"
+//! # Refuse to bury anything that would leave less than this free
+//! min_free = "1G"
+//! ```
+use serde::Deserialize;
+use std::{
+    env, fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::errors::*;
+use crate::gc::Retention;
+use crate::graveyard::Conflict;
+use crate::quota::Limits;
+use crate::util::{parse_duration, parse_size};
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub gc: Gc,
+    pub unbury: Unbury,
+    pub quota: Quota,
+}
+
+/// How graves are expired by `rip gc`
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Gc {
+    /// Expire graves that were buried longer ago than this, e.g., `30d`
+    pub older_than: Option<String>,
+    /// Expire the oldest graves while the graveyard is bigger than this, e.g., `10G`
+    pub max_size: Option<String>,
+    /// Collect garbage after each burial
+    pub auto: bool,
+}
+
+/// How graves are unburied
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Unbury {
+    /// What to do when a grave is unburied to a path that is taken
+    pub conflict: Option<Conflict>,
+}
+
+/// Limits on the space taken by the graveyard
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Quota {
+    /// Most space the graves can take, e.g., `20G`
+    pub max_size: Option<String>,
+    /// Least space to leave free on the filesystem of the graveyard, e.g., `1G`
+    pub min_free: Option<String>,
+}
+
+impl Config {
+    /// Where the config is read from
+    pub fn path() -> Option<PathBuf> {
+        if let Some(path) = env::var_os("RIP_CONFIG") {
+            return Some(PathBuf::from(path));
+        }
+        let base = match env::var_os("XDG_CONFIG_HOME") {
+            Some(dir) => PathBuf::from(dir),
+            None => PathBuf::from(env::var_os("HOME")?).join(".config"),
+        };
+        Some(base.join("rip").join("config.toml"))
+    }
+
+    /// Read the config from where it is usually kept
+    pub fn load() -> Result<Config> {
+        match Config::path() {
+            Some(path) => Config::from_file(path),
+            None => Ok(Config::default()),
+        }
+    }
+
+    /// Read the config at `path`, if there is one
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Config> {
+        let path = path.as_ref();
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Config::default()),
+            Err(e) => {
+                return Err(e).chain_err(|| format!("Couldn't read config at {}", path.display()))
+            },
+        };
+        toml::from_str(&contents)
+            .map_err(|e| format!("Invalid config at {}: {}", path.display(), e).into())
+    }
+}
+
+impl Gc {
+    /// The retention policy these settings describe
+    pub fn retention(&self) -> Result<Retention> {
+        Ok(Retention {
+            older_than: self
+                .older_than
+                .as_deref()
+                .map(|age| parse_duration(age).ok_or_else(|| invalid("gc.older_than", age)))
+                .transpose()?,
+            max_size: self
+                .max_size
+                .as_deref()
+                .map(|size| parse_size(size).ok_or_else(|| invalid("gc.max_size", size)))
+                .transpose()?,
+            ..Retention::default()
+        })
+    }
+}
+
+impl Quota {
+    /// The limits these settings describe
+    pub fn limits(&self) -> Result<Limits> {
+        Ok(Limits {
+            max_size: self
+                .max_size
+                .as_deref()
+                .map(|size| parse_size(size).ok_or_else(|| invalid("quota.max_size", size)))
+                .transpose()?,
+            min_free: self
+                .min_free
+                .as_deref()
+                .map(|size| parse_size(size).ok_or_else(|| invalid("quota.min_free", size)))
+                .transpose()?
+                .unwrap_or(0),
+        })
+    }
+}
+
+fn invalid(setting: &str, value: &str) -> Error {
+    format!("Invalid value for {} in config: {}", setting, value).into()
+}
@@ -0,0 +1,119 @@
+//! Copying the contents of regular files without materializing their holes.
+//!
+//! On Linux the copy is first attempted as a reflink, which shares the data
+//! with the source on filesystems like Btrfs and XFS. Otherwise only the
+//! regions that hold data (found with `SEEK_DATA`/`SEEK_HOLE`) are copied,
+//! using `copy_file_range` where possible, and the holes are left as holes.
+//! Copying stops with [`io::ErrorKind::Interrupted`] once a signal is caught.
+use std::{fs, io, path::Path};
+
+use crate::interrupt;
+
+/// Copy the contents of the regular file `source` to `dest`, which is created
+/// or truncated. Returns the number of bytes in the file.
+#[cfg(target_os = "linux")]
+pub(crate) fn copy_contents(source: &Path, dest: &Path) -> io::Result<u64> {
+    use std::os::unix::io::AsRawFd;
+
+    let src = fs::File::open(source)?;
+    let dst = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(dest)?;
+    let len = src.metadata()?.len();
+
+    if unsafe { libc::ioctl(dst.as_raw_fd(), libc::FICLONE, src.as_raw_fd()) } == 0 {
+        return Ok(len);
+    }
+
+    let mut pos = 0;
+    while pos < len {
+        interrupt::check()?;
+        let data = match seek(&src, pos, libc::SEEK_DATA) {
+            Ok(data) => data,
+            // Only holes are left
+            Err(e) if e.raw_os_error() == Some(libc::ENXIO) => break,
+            // Holes can't be found on this filesystem, so copy everything
+            Err(_) => {
+                copy_range(&src, &dst, 0, len)?;
+                break;
+            },
+        };
+        let hole = seek(&src, data, libc::SEEK_HOLE)?.min(len);
+        copy_range(&src, &dst, data, hole - data)?;
+        pos = hole;
+    }
+
+    // Trailing holes aren't written, so give the file its full length
+    dst.set_len(len)?;
+    Ok(len)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn copy_contents(source: &Path, dest: &Path) -> io::Result<u64> {
+    fs::copy(source, dest)
+}
+
+#[cfg(target_os = "linux")]
+fn seek(f: &fs::File, offset: u64, whence: libc::c_int) -> io::Result<u64> {
+    use std::os::unix::io::AsRawFd;
+
+    let ret = unsafe { libc::lseek(f.as_raw_fd(), offset as libc::off_t, whence) };
+    if ret == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(ret as u64)
+}
+
+/// Copy `len` bytes at `offset` in `src` to the same offset in `dst`
+#[cfg(target_os = "linux")]
+fn copy_range(src: &fs::File, dst: &fs::File, offset: u64, len: u64) -> io::Result<()> {
+    use std::os::unix::{fs::FileExt, io::AsRawFd};
+
+    let (mut off_in, mut off_out) = (offset as libc::loff_t, offset as libc::loff_t);
+    let end = offset + len;
+
+    while (off_in as u64) < end {
+        interrupt::check()?;
+        let n = unsafe {
+            libc::copy_file_range(
+                src.as_raw_fd(),
+                &mut off_in,
+                dst.as_raw_fd(),
+                &mut off_out,
+                (end - off_in as u64) as usize,
+                0,
+            )
+        };
+        match n {
+            // The file was truncated while copying
+            0 => return Ok(()),
+            -1 => {
+                let e = io::Error::last_os_error();
+                match e.raw_os_error() {
+                    // Not supported between these files, so copy it ourselves
+                    Some(libc::EXDEV) | Some(libc::ENOSYS) | Some(libc::EOPNOTSUPP)
+                    | Some(libc::EINVAL) => break,
+                    _ => return Err(e),
+                }
+            },
+            _ => {},
+        }
+    }
+
+    let mut buf = vec![0; 1 << 16];
+    let mut pos = off_in as u64;
+    while pos < end {
+        interrupt::check()?;
+        let want = buf.len().min((end - pos) as usize);
+        let n = src.read_at(&mut buf[..want], pos)?;
+        if n == 0 {
+            break;
+        }
+        dst.write_all_at(&buf[..n], pos)?;
+        pos += n as u64;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,225 @@
+//! Finding and repairing the ways the record and the graveyard can disagree.
+//!
+//! Graves can end up on disk without a line in the record (e.g., when the
+//! record was edited by hand, or written by a `rip` that was killed before it
+//! had a journal), and the record can have lines for graves that are gone,
+//! the same grave more than once, graves outside of the graveyard, or lines
+//! that can't be parsed at all.
+use chrono::{Local, TimeZone};
+use std::{
+    collections::HashSet,
+    fs,
+    os::unix::{ffi::OsStrExt, fs::MetadataExt},
+    path::{Path, PathBuf},
+};
+use walkdir::{WalkDir, WalkDirIterator};
+
+use crate::errors::*;
+use crate::record::RecordItem;
+use crate::util::symlink_exists;
+use crate::{Graveyard, JOURNAL, LOCK, RECORD};
+
+/// A disagreement between the record and the graveyard
+#[derive(Debug, Clone, PartialEq)]
+pub enum Problem {
+    /// A grave with no line in the record, along with where it was probably
+    /// buried from
+    Orphan { grave: PathBuf, orig: PathBuf },
+    /// A line for a grave that is no longer in the graveyard
+    Dangling(RecordItem),
+    /// A line for a grave that already has an earlier line
+    Duplicate(RecordItem),
+    /// A line for a grave outside of the graveyard
+    Outside(RecordItem),
+    /// A line that can't be parsed
+    Malformed(Vec<u8>),
+}
+
+impl Graveyard {
+    /// Compare the record with what is in the graveyard, returning every
+    /// problem that was found
+    pub fn examine(&self) -> Result<Vec<Problem>> {
+        let mut problems = Vec::new();
+        let entries = self.entries()?;
+
+        let mut seen = HashSet::new();
+        for entry in &entries {
+            if !self.contains(&entry.dest) {
+                problems.push(Problem::Outside(entry.clone()));
+            } else if !seen.insert(&entry.dest) {
+                problems.push(Problem::Duplicate(entry.clone()));
+            } else if !symlink_exists(&entry.dest) {
+                problems.push(Problem::Dangling(entry.clone()));
+            }
+        }
+
+        problems.extend(self.orphans(&entries)?);
+
+        problems.extend(
+            self.record()
+                .malformed()
+                .chain_err(|| "Failed to read record")?
+                .into_iter()
+                .map(Problem::Malformed),
+        );
+
+        Ok(problems)
+    }
+
+    /// Repair each of `problems`: orphans are added to the record, and every
+    /// other problem is fixed by removing its line from the record
+    pub fn repair(&self, problems: &[Problem]) -> Result<()> {
+        let mut dropped: HashSet<&Path> = HashSet::new();
+        let mut duplicated: HashSet<&Path> = HashSet::new();
+        let mut malformed = false;
+        for problem in problems {
+            match problem {
+                // Only drop lines whose graves are still missing
+                Problem::Dangling(entry) if !symlink_exists(&entry.dest) => {
+                    dropped.insert(&entry.dest);
+                },
+                Problem::Outside(entry) => {
+                    dropped.insert(&entry.dest);
+                },
+                Problem::Duplicate(entry) => {
+                    duplicated.insert(&entry.dest);
+                },
+                Problem::Malformed(_) => malformed = true,
+                _ => {},
+            }
+        }
+
+        // Keep the first line of each duplicated grave
+        let mut seen = HashSet::new();
+        self.record()
+            .retain(|entry| {
+                !dropped.contains(entry.dest.as_path())
+                    && (!duplicated.contains(entry.dest.as_path()) || seen.insert(entry.dest.clone()))
+            })
+            .chain_err(|| "Failed to remove lines from record")?;
+
+        if malformed {
+            self.record()
+                .remove_malformed()
+                .chain_err(|| "Failed to remove malformed lines from record")?;
+        }
+
+        for problem in problems {
+            if let Problem::Orphan { grave, orig } = problem {
+                // Moving a file into the graveyard changes its ctime, which is
+                // as close to the time of its burial as we can get
+                let metadata = fs::symlink_metadata(grave)
+                    .chain_err(|| format!("Couldn't adopt {}", grave.display()))?;
+                let deleted = Local
+                    .timestamp_opt(metadata.ctime(), 0)
+                    .single()
+                    .unwrap_or_else(Local::now);
+                self.record()
+                    .append(&RecordItem {
+                        deleted: deleted.into(),
+                        orig: orig.clone(),
+                        dest: grave.clone(),
+                        placeholder: false,
+                        digest: None,
+                        pinned: false,
+                    })
+                    .chain_err(|| format!("Couldn't adopt {}", grave.display()))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Find the graves that have no line in the record. A directory that isn't
+    /// a grave is only looked into if it holds graves, or if it was a directory
+    /// when it was buried from, i.e., if it still exists outside the graveyard.
+    /// Otherwise it is taken to be a single grave, unless it has no files in it.
+    fn orphans(&self, entries: &[RecordItem]) -> Result<Vec<Problem>> {
+        let graves: HashSet<&Path> = entries.iter().map(|entry| entry.dest.as_path()).collect();
+        let holders: HashSet<&Path> = graves
+            .iter()
+            .flat_map(|grave| grave.ancestors().skip(1))
+            .collect();
+        // Graves that are being buried or exhumed right now aren't recorded yet
+        let claimed: HashSet<PathBuf> = self
+            .journal()
+            .intents()
+            .chain_err(|| "Couldn't read the journal")?
+            .into_iter()
+            .flat_map(|intent| vec![intent.from, intent.to])
+            .collect();
+        let internal = [
+            self.path().join(RECORD),
+            self.path().join(format!("{}.tmp", RECORD)),
+            self.path().join(JOURNAL),
+            self.path().join(LOCK),
+        ];
+
+        let mut orphans = Vec::new();
+        let mut walker = WalkDir::new(self.path())
+            .min_depth(1)
+            .sort_by(|a, b| a.cmp(b))
+            .into_iter();
+        while let Some(entry) = walker.next() {
+            let entry = entry.chain_err(|| "Couldn't read the graveyard")?;
+            let path = entry.path();
+            let is_dir = entry.file_type().is_dir();
+
+            if graves.contains(path) || claimed.contains(path) || internal.iter().any(|p| p == path) {
+                if is_dir {
+                    walker.skip_current_dir();
+                }
+                continue;
+            }
+            if is_dir
+                && (holders.contains(path)
+                    || fs::symlink_metadata(self.origin(path)).is_ok_and(|m| m.is_dir()))
+            {
+                continue;
+            }
+            if is_dir {
+                walker.skip_current_dir();
+                // Leftovers of graves that were exhumed
+                if !has_files(path) {
+                    continue;
+                }
+            }
+
+            orphans.push(Problem::Orphan {
+                grave: path.to_path_buf(),
+                orig: strip_number(&self.origin(path)),
+            });
+        }
+
+        Ok(orphans)
+    }
+
+    /// Where something in the graveyard at `grave` would have been buried from
+    fn origin(&self, grave: &Path) -> PathBuf {
+        Path::new("/").join(grave.strip_prefix(self.path()).unwrap_or(grave))
+    }
+}
+
+/// Whether there is anything other than directories under `dir`
+fn has_files(dir: &Path) -> bool {
+    WalkDir::new(dir)
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .any(|entry| !entry.file_type().is_dir())
+}
+
+/// Remove the `~N` that is added to the names of graves that would have had
+/// the same path as another
+fn strip_number(path: &Path) -> PathBuf {
+    let bytes = path.as_os_str().as_bytes();
+    if let Some(tilde) = bytes.iter().rposition(|&b| b == b'~') {
+        let number = &bytes[tilde + 1..];
+        if !number.is_empty()
+            && number.iter().all(u8::is_ascii_digit)
+            && !bytes[..tilde].ends_with(b"/")
+        {
+            return PathBuf::from(std::ffi::OsStr::from_bytes(&bytes[..tilde]));
+        }
+    }
+    path.to_path_buf()
+}
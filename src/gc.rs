@@ -0,0 +1,81 @@
+//! Expiring graves that have been in the graveyard for too long, or that don't
+//! fit in the space the graveyard is allowed to take.
+use chrono::Duration;
+use std::path::PathBuf;
+
+use crate::errors::*;
+use crate::record::RecordItem;
+use crate::util::cutoff;
+use crate::Graveyard;
+
+/// Which graves are expired by [`Graveyard::gc`]
+#[derive(Debug, Clone, Default)]
+pub struct Retention {
+    /// Expire graves that were buried longer ago than this
+    pub older_than: Option<Duration>,
+    /// Expire the oldest graves while the graves take more space than this
+    pub max_size: Option<u64>,
+    /// Graves that are never expired, e.g., the ones that were just buried
+    pub spare: Vec<PathBuf>,
+    /// Expire pinned graves too
+    pub force: bool,
+}
+
+impl Retention {
+    /// Whether the policy doesn't expire anything
+    pub fn is_empty(&self) -> bool {
+        self.older_than.is_none() && self.max_size.is_none()
+    }
+}
+
+impl Graveyard {
+    /// Return the entries that `retention` expires, oldest first, along with
+    /// the space their graves take. These are the graves buried longer ago
+    /// than `older_than`, and then the oldest of the rest until the ones that
+    /// are left fit in `max_size`. Pinned graves are only expired if forced.
+    pub fn expired(&self, retention: &Retention) -> Result<Vec<(RecordItem, u64)>> {
+        let (mut entries, spared): (Vec<_>, Vec<_>) = self.entries()?.into_iter().partition(|entry| {
+            !retention.spare.contains(&entry.dest) && (retention.force || !entry.pinned)
+        });
+        entries.sort_by_key(|entry| entry.deleted.timestamp());
+
+        let mut expired = Vec::new();
+        let mut kept = Vec::new();
+        let cutoff = match retention.older_than {
+            Some(age) => Some(cutoff(age).ok_or("The age of the graves to expire is too large")?),
+            None => None,
+        };
+        for entry in entries {
+            let size = self.grave_size(&entry.dest);
+            if cutoff.is_some_and(|cutoff| entry.deleted.timestamp() < cutoff) {
+                expired.push((entry, size));
+            } else {
+                kept.push((entry, size));
+            }
+        }
+
+        if let Some(max_size) = retention.max_size {
+            // Spared graves take up space too
+            let mut total = kept.iter().map(|(_, size)| size).sum::<u64>()
+                + spared.iter().map(|entry| self.grave_size(&entry.dest)).sum::<u64>();
+            for (entry, size) in kept {
+                if total <= max_size {
+                    break;
+                }
+                total -= size;
+                expired.push((entry, size));
+            }
+        }
+
+        Ok(expired)
+    }
+
+    /// Permanently delete the graves that `retention` expires, returning their
+    /// entries along with the space that was freed by each
+    pub fn gc(&self, retention: &Retention) -> Result<Vec<(RecordItem, u64)>> {
+        let expired = self.expired(retention)?;
+        let graves = expired.iter().map(|(entry, _)| entry.dest.clone()).collect::<Vec<_>>();
+        self.purge(&graves)?;
+        Ok(expired)
+    }
+}
@@ -0,0 +1,732 @@
+use chrono::Local;
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    env,
+    ffi::OsString,
+    fs, io,
+    os::unix::fs::FileTypeExt,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+use crate::bury::{remove, remove_source, restore_socket, transfer, undo, Moved};
+use crate::checksum;
+use crate::errors::*;
+use crate::interrupt;
+use crate::journal::{Intent, Journal, Operation, Recovery};
+use crate::record::{Record, RecordItem};
+use crate::lock::Lock;
+use crate::quota::Limits;
+use crate::util::{get_user, join_absolute, rename_grave_with, symlink_exists};
+use crate::{GRAVEYARD, JOURNAL, LOCK, RECORD};
+
+/// The directory deleted files are sent to, along with its record
+#[derive(Debug, Clone)]
+pub struct Graveyard {
+    path: PathBuf,
+    record: Record,
+    journal: Journal,
+    checksum: bool,
+    markers: bool,
+    pub(crate) limits: Limits,
+    conflict: Conflict,
+    /// Space taken by the graves that have been measured, which is shared by
+    /// clones so that each grave is only walked once
+    pub(crate) sizes: Arc<Mutex<HashMap<PathBuf, u64>>>,
+}
+
+/// Result of checking a grave against the checksum in its record
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    /// The grave is as it was when it was buried
+    Intact,
+    /// The grave changed since it was buried
+    Changed,
+    /// The grave is no longer in the graveyard
+    Missing,
+    /// No checksum was recorded for the grave
+    Unrecorded,
+}
+
+/// What to do when a grave is unburied to a path that is taken
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Conflict {
+    /// Restore the grave under a numbered name, e.g., `file~1`
+    Rename,
+    /// Bury what is at the path, then restore the grave there
+    Overwrite,
+    /// Leave the grave in the graveyard
+    Skip,
+    /// Ask which of the others to do. The library can't ask, so it stops with
+    /// [`ErrorKind::Taken`], and the caller asks and tries again with the
+    /// strategy that was picked.
+    Prompt,
+    /// Stop with an error
+    Fail,
+}
+
+impl FromStr for Conflict {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "rename" => Ok(Conflict::Rename),
+            "overwrite" => Ok(Conflict::Overwrite),
+            "skip" => Ok(Conflict::Skip),
+            "prompt" => Ok(Conflict::Prompt),
+            "fail" => Ok(Conflict::Fail),
+            _ => bail!("Invalid conflict strategy: {}", s),
+        }
+    }
+}
+
+/// An extended attribute that couldn't be carried over to a copy
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Dropped {
+    /// The copy that is missing the attribute
+    pub path: PathBuf,
+    /// Name of the attribute
+    pub name: OsString,
+    /// Why it couldn't be set
+    pub reason: String,
+}
+
+/// A file that was sent to the graveyard
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Burial {
+    /// Where it is buried
+    pub grave: PathBuf,
+    /// Extended attributes that couldn't be carried over to the grave
+    pub dropped: Vec<Dropped>,
+}
+
+/// A grave that was unburied, or was left alone
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Exhumed {
+    /// The grave that was picked
+    pub grave: PathBuf,
+    /// What happened to it
+    pub outcome: Outcome,
+    /// Extended attributes that couldn't be carried over to where it went
+    pub dropped: Vec<Dropped>,
+}
+
+/// What happened to a grave that was unburied
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    /// It was restored to its path
+    Restored(PathBuf),
+    /// Its path was taken, so it was restored under a numbered name
+    Renamed(PathBuf),
+    /// It was restored to its path after burying what was there
+    Replaced { path: PathBuf, buried: PathBuf },
+    /// Its path was taken, so it was left in the graveyard
+    Skipped(PathBuf),
+}
+
+impl Graveyard {
+    /// Use `path` as the graveyard
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        let path = path.into();
+        let record = Record::new(path.join(RECORD));
+        let journal = Journal::new(path.join(JOURNAL));
+        Graveyard {
+            path,
+            record,
+            journal,
+            checksum: false,
+            markers: false,
+            limits: Limits::default(),
+            conflict: Conflict::Rename,
+            sizes: Arc::default(),
+        }
+    }
+
+    /// Check the contents of copies across filesystems against their source,
+    /// and record a checksum of each grave so that it can be verified later
+    pub fn checksum(mut self, checksum: bool) -> Self {
+        self.checksum = checksum;
+        self
+    }
+
+    /// Permanently delete device nodes that can't be made in the graveyard,
+    /// which takes privileges, leaving marker files in their place. Otherwise
+    /// burying them fails with [`ErrorKind::NoDevice`].
+    pub fn markers(mut self, markers: bool) -> Self {
+        self.markers = markers;
+        self
+    }
+
+    /// Keep the graveyard within `limits`, refusing to bury anything that
+    /// would go over them
+    pub fn limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Do `conflict` when a grave is unburied to a path that is taken
+    pub fn conflict(mut self, conflict: Conflict) -> Self {
+        self.conflict = conflict;
+        self
+    }
+
+    /// Find the graveyard the same way `rip` does: `flag` (from `-G`) if given,
+    /// else `$GRAVEYARD`, else `$XDG_DATA_HOME/graveyard`, else `/tmp/graveyard-$USER`
+    pub fn resolve(flag: Option<&str>) -> Self {
+        Graveyard::new(if let Some(flag) = flag {
+            flag.to_owned()
+        } else if let Ok(env) = env::var("GRAVEYARD") {
+            env
+        } else if let Ok(mut env) = env::var("XDG_DATA_HOME") {
+            if !env.ends_with(std::path::MAIN_SEPARATOR) {
+                env.push(std::path::MAIN_SEPARATOR);
+            }
+            env.push_str("graveyard");
+            env
+        } else {
+            format!("{}-{}", GRAVEYARD, get_user())
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn record(&self) -> &Record {
+        &self.record
+    }
+
+    pub fn journal(&self) -> &Journal {
+        &self.journal
+    }
+
+    /// Whether `path` lives inside the graveyard
+    pub fn contains<P: AsRef<Path>>(&self, path: P) -> bool {
+        path.as_ref().starts_with(&self.path)
+    }
+
+    /// Send `source` to the graveyard and record it, returning the path of its
+    /// grave along with anything that couldn't be carried over to it.
+    /// Relative paths are taken to be relative to the current directory.
+    ///
+    /// Fails with [`ErrorKind::NoRoom`] before anything is moved if `source`
+    /// doesn't fit within the limits of the graveyard, and with
+    /// [`ErrorKind::NoDevice`] if it has device nodes that can't be made in
+    /// the graveyard, unless [`Graveyard::markers`] is set.
+    pub fn bury<S: AsRef<Path>>(&self, source: S) -> Result<Burial> {
+        let source = &env::current_dir()
+            .chain_err(|| "Failed to get current dir")?
+            .join(source);
+
+        if self.contains(source) {
+            bail!("{} is already in the graveyard", source.display());
+        }
+
+        if let Some(shortfall) = self.room_for(source)? {
+            bail!(ErrorKind::NoRoom(source.to_path_buf(), shortfall));
+        }
+
+        let is_socket = fs::symlink_metadata(source).is_ok_and(|m| m.file_type().is_socket());
+
+        // Resolve a name conflict if necessary
+        let (dest, lock) = self.claim(join_absolute(&self.path, source))?;
+        let mut intent = Intent::new(Operation::Bury, source, &dest, RecordItem {
+            deleted: Local::now().into(),
+            orig: source.to_path_buf(),
+            dest: dest.clone(),
+            placeholder: false,
+            digest: None,
+            pinned: false,
+        });
+        self.journal.write(&intent).chain_err(|| self.journal_error())?;
+        drop(lock);
+
+        let (moved, dropped) = match transfer(source, &dest, self.markers, self.checksum) {
+            Ok(transferred) => transferred,
+            Err(e) => {
+                self.journal.clear(&intent).ok();
+                // Left as it is for the caller to pick out, like NoRoom
+                if let ErrorKind::NoDevice(..) = e.kind() {
+                    return Err(e);
+                }
+                return Err(e).chain_err(|| "Failed to bury file");
+            },
+        };
+        // Put the source back if the burial can't be recorded
+        let claimed = intent.clone();
+        let abandon = |e: Error| -> Error {
+            undo(source, &dest, moved).ok();
+            self.journal.clear(&claimed).ok();
+            e
+        };
+
+        // The socket couldn't be recreated in the graveyard
+        intent.entry.placeholder =
+            is_socket && fs::symlink_metadata(&dest).is_ok_and(|m| m.is_file());
+        if self.checksum {
+            intent.entry.digest = Some(
+                checksum::digest(&dest)
+                    .chain_err(|| format!("Couldn't checksum {}", dest.display()))
+                    .map_err(abandon)?,
+            );
+        }
+        intent.moved = true;
+        self.journal
+            .write(&intent)
+            .chain_err(|| self.journal_error())
+            .map_err(abandon)?;
+
+        self.finish(&intent, moved == Moved::Copied)?;
+        Ok(Burial { grave: dest, dropped })
+    }
+
+    /// Return each of `graves` to where it was buried from, removing them from
+    /// the record. If the original path is taken, what is done depends on the
+    /// [`Conflict`] strategy of the graveyard. Returns each grave along with
+    /// what happened to it.
+    ///
+    /// A path inside of a buried directory can be given too, in which case
+    /// only that path is returned, and the rest of the directory stays buried.
+    ///
+    /// Stops at the first grave that can't be restored.
+    pub fn unbury(&self, graves: &[PathBuf]) -> Result<Vec<Exhumed>> {
+        self.exhume(graves, |entry| entry.orig.clone())
+    }
+
+    /// Restore each of `graves` under `dir` rather than where it was buried
+    /// from. With `parents`, the original path is kept under `dir`: the part
+    /// of it below the current directory, or all of it if it isn't below the
+    /// current directory. Otherwise only the name is kept. A relative `dir` is
+    /// taken to be relative to the current directory.
+    /// Otherwise the same as [`Graveyard::unbury`].
+    pub fn unbury_to<P: AsRef<Path>>(
+        &self,
+        graves: &[PathBuf],
+        dir: P,
+        parents: bool,
+    ) -> Result<Vec<Exhumed>> {
+        let cwd = env::current_dir().chain_err(|| "Failed to get current dir")?;
+        let dir = cwd.join(dir);
+        self.exhume(graves, |entry| match entry.orig.strip_prefix(&cwd) {
+            Ok(relative) if parents => dir.join(relative),
+            Err(_) if parents => join_absolute(&dir, &entry.orig),
+            _ => dir.join(entry.orig.file_name().unwrap_or(entry.orig.as_os_str())),
+        })
+    }
+
+    /// Move each of `graves` out of the graveyard to the path `target` picks
+    /// for its entry
+    fn exhume<F>(&self, graves: &[PathBuf], target: F) -> Result<Vec<Exhumed>>
+    where
+        F: Fn(&RecordItem) -> PathBuf,
+    {
+        let mut exhumed = Vec::new();
+
+        let entries = self.record.entries().chain_err(|| "Couldn't read the record")?;
+        let mut chosen = entries
+            .iter()
+            .filter(|e| graves.contains(&e.dest))
+            .cloned()
+            .collect::<Vec<_>>();
+        // Paths inside of buried directories, unless the whole directory is
+        // being returned anyway
+        for grave in graves.iter().filter(|g| symlink_exists(g)) {
+            let within = entries
+                .iter()
+                .filter(|e| grave.starts_with(&e.dest) && *grave != e.dest)
+                .max_by_key(|e| e.dest.as_os_str().len());
+            if let Some(parent) = within {
+                if !graves.contains(&parent.dest) && !chosen.iter().any(|e| e.dest == *grave) {
+                    let relative = grave.strip_prefix(&parent.dest).unwrap_or(grave);
+                    chosen.push(RecordItem {
+                        orig: parent.orig.join(relative),
+                        dest: grave.clone(),
+                        placeholder: false,
+                        digest: None,
+                        pinned: false,
+                        ..parent.clone()
+                    });
+                }
+            }
+        }
+
+        for entry in chosen {
+            if interrupt::requested() {
+                bail!(ErrorKind::Interrupted(entry.dest));
+            }
+            let wanted = target(&entry);
+            let mut replaced = None;
+            if symlink_exists(&wanted) {
+                match self.conflict {
+                    Conflict::Overwrite => {
+                        let buried = self.bury(&wanted).chain_err(|| {
+                            format!("Couldn't bury {} to make way for its grave", wanted.display())
+                        })?;
+                        replaced = Some(buried.grave);
+                    },
+                    Conflict::Skip => {
+                        exhumed.push(Exhumed {
+                            grave: entry.dest,
+                            outcome: Outcome::Skipped(wanted),
+                            dropped: Vec::new(),
+                        });
+                        continue;
+                    },
+                    Conflict::Fail | Conflict::Prompt => bail!(ErrorKind::Taken(entry.dest, wanted)),
+                    Conflict::Rename => {},
+                }
+            }
+            // The path is numbered if it was taken since it was checked
+            let (orig, lock) = self.claim(wanted.clone())?;
+            let mut intent = Intent::new(Operation::Unbury, &entry.dest, &orig, entry.clone());
+            self.journal.write(&intent).chain_err(|| self.journal_error())?;
+            drop(lock);
+
+            let (moved, dropped) = match transfer(&entry.dest, &orig, self.markers, self.checksum) {
+                Ok(transferred) => transferred,
+                Err(e) => {
+                    self.journal.clear(&intent).ok();
+                    return Err(e).chain_err(|| {
+                        format!(
+                            "Unbury failed: couldn't copy files from {} to {}",
+                            entry.dest.display(),
+                            orig.display()
+                        )
+                    });
+                },
+            };
+            intent.moved = true;
+            if let Err(e) = self.journal.write(&intent) {
+                undo(&entry.dest, &orig, moved).ok();
+                self.journal.clear(&intent).ok();
+                return Err(e).chain_err(|| self.journal_error());
+            }
+
+            self.finish(&intent, moved == Moved::Copied)?;
+            let outcome = match replaced {
+                Some(buried) => Outcome::Replaced { path: orig, buried },
+                None if orig != wanted => Outcome::Renamed(orig),
+                None => Outcome::Restored(orig),
+            };
+            exhumed.push(Exhumed { grave: entry.dest, outcome, dropped });
+        }
+
+        Ok(exhumed)
+    }
+
+    /// Lock the graveyard, and pick a path for something to be moved to at
+    /// `path`, numbering it if it is taken. Paths in the journal are taken by
+    /// other operations, so the lock has to be held until the intent that
+    /// uses the path has been written.
+    fn claim(&self, path: PathBuf) -> Result<(PathBuf, Lock)> {
+        let lock = self.lock()?;
+        let claimed = self
+            .journal
+            .intents()
+            .chain_err(|| format!("Couldn't read the journal at {}", self.journal.path().display()))?
+            .into_iter()
+            .map(|intent| intent.to)
+            .collect::<Vec<_>>();
+        let taken = |p: &Path| symlink_exists(p) || claimed.iter().any(|c| c == p);
+
+        let path = if taken(&path) { rename_grave_with(&path, taken) } else { path };
+        Ok((path, lock))
+    }
+
+    /// Wait until no other `rip` is changing the graveyard
+    fn lock(&self) -> Result<Lock> {
+        Lock::exclusive(self.path.join(LOCK))
+            .chain_err(|| format!("Couldn't lock the graveyard at {}", self.path.display()))
+    }
+
+    /// Complete an operation once everything is at `intent.to`: remove the
+    /// source if it was `copied`, update the record and clear the journal
+    fn finish(&self, intent: &Intent, copied: bool) -> Result<()> {
+        let entry = &intent.entry;
+        self.forget_size(&entry.dest);
+
+        // A failed removal leaves the source where it was, unless it was only
+        // partly removed, in which case the operation still has to be completed
+        let partial = if copied {
+            match remove_source(&intent.from, &intent.to) {
+                Ok(()) => None,
+                Err(e @ Error(ErrorKind::PartialRemoval(_), _)) => Some(e),
+                Err(e) => {
+                    self.journal.clear(intent).ok();
+                    return Err(e);
+                },
+            }
+        } else {
+            None
+        };
+
+        let mut socket = Ok(());
+        match intent.op {
+            Operation::Bury => {
+                // It may have been recorded before being interrupted
+                let recorded = self
+                    .record
+                    .entries()
+                    .chain_err(|| "Couldn't read the record")?
+                    .iter()
+                    .any(|e| e.dest == entry.dest);
+                if !recorded {
+                    self.record.append(entry).chain_err(|| {
+                        format!("Failed to write record at {}", self.record.path().display())
+                    })?;
+                }
+            },
+            Operation::Unbury => {
+                if entry.placeholder && fs::symlink_metadata(&intent.to).is_ok_and(|m| m.is_file())
+                {
+                    socket = restore_socket(&intent.to).chain_err(|| {
+                        format!("Couldn't restore socket at {}", intent.to.display())
+                    });
+                }
+                // Delete the line corresponding to the exhumed grave
+                self.record
+                    .remove(std::slice::from_ref(&entry.dest))
+                    .chain_err(|| "Failed to remove unburied files from record")?;
+                // If only part of a buried directory was returned, the
+                // checksum of what is left has changed
+                let within = |parent: &RecordItem| {
+                    parent.digest.is_some()
+                        && entry.dest.starts_with(&parent.dest)
+                        && entry.dest != parent.dest
+                };
+                if self
+                    .record
+                    .entries()
+                    .chain_err(|| "Couldn't read the record")?
+                    .iter()
+                    .any(within)
+                {
+                    self.record
+                        .update(|parent| {
+                            if within(parent) {
+                                parent.digest = checksum::digest(&parent.dest).ok();
+                            }
+                        })
+                        .chain_err(|| "Failed to update checksum in record")?;
+                }
+            },
+        }
+        self.journal.clear(intent).chain_err(|| self.journal_error())?;
+
+        match partial {
+            Some(e) => Err(e),
+            None => socket,
+        }
+    }
+
+    /// Complete or roll back the operations that were interrupted, e.g.,
+    /// because `rip` was killed while copying a directory. An operation is
+    /// completed if everything got to its destination, and is rolled back
+    /// otherwise.
+    pub fn recover(&self) -> Result<Vec<Recovery>> {
+        let mut recovered = Vec::new();
+
+        let interrupted = || {
+            self.journal.interrupted().chain_err(|| {
+                format!("Couldn't read the journal at {}", self.journal.path().display())
+            })
+        };
+        if interrupted()?.is_empty() {
+            return Ok(recovered);
+        }
+
+        loop {
+            // Take over the intent, so that no other rip recovers it at the
+            // same time. It is kept under a name of its own, so that it isn't
+            // replaced by an operation of this process if recovering it fails.
+            let intent = {
+                let _lock = self.lock()?;
+                let dead = match interrupted()?.into_iter().next() {
+                    Some(intent) => intent,
+                    None => break,
+                };
+                let intent = Intent::take_over(&dead);
+                self.journal.write(&intent).chain_err(|| self.journal_error())?;
+                self.journal.clear(&dead).chain_err(|| self.journal_error())?;
+                intent
+            };
+
+            let (from, to) = (symlink_exists(&intent.from), symlink_exists(&intent.to));
+            // A rename is never interrupted, so a missing source means it happened
+            if to && (intent.moved || !from) {
+                self.finish(&intent, from)?;
+                recovered.push(Recovery::Completed(intent));
+            } else {
+                if !intent.moved && from && to {
+                    remove(&intent.to)
+                        .chain_err(|| format!("Couldn't remove {}", intent.to.display()))?;
+                }
+                self.journal.clear(&intent).chain_err(|| self.journal_error())?;
+                recovered.push(Recovery::RolledBack(intent));
+            }
+        }
+
+        Ok(recovered)
+    }
+
+    fn journal_error(&self) -> String {
+        format!("Failed to write journal at {}", self.journal.path().display())
+    }
+
+    /// Check the grave of `entry` against the checksum taken when it was buried
+    pub fn verify(&self, entry: &RecordItem) -> Result<Verdict> {
+        let recorded = match &entry.digest {
+            Some(digest) => digest,
+            None => return Ok(Verdict::Unrecorded),
+        };
+        if !symlink_exists(&entry.dest) {
+            return Ok(Verdict::Missing);
+        }
+        let digest = checksum::digest(&entry.dest)
+            .chain_err(|| format!("Couldn't checksum {}", entry.dest.display()))?;
+        Ok(if &digest == recorded { Verdict::Intact } else { Verdict::Changed })
+    }
+
+    /// Pin each of `graves`, or unpin them if `pinned` is false. Pinned graves
+    /// are spared by [`Graveyard::gc`] and [`Graveyard::decompose`] unless
+    /// that is forced. Returns the entries that were changed.
+    pub fn pin(&self, graves: &[PathBuf], pinned: bool) -> Result<Vec<RecordItem>> {
+        let mut changed = Vec::new();
+        self.record
+            .update(|entry| {
+                if entry.pinned != pinned && graves.contains(&entry.dest) {
+                    entry.pinned = pinned;
+                    changed.push(entry.clone());
+                }
+            })
+            .chain_err(|| "Failed to update record")?;
+        Ok(changed)
+    }
+
+    /// Return the entries of every file that was buried from under `dir`, or
+    /// of every file in the graveyard if `dir` is `None`
+    pub fn list(&self, dir: Option<&Path>) -> Result<Vec<RecordItem>> {
+        let gravepath = match dir {
+            Some(dir) => join_absolute(&self.path, dir),
+            None => self.path.clone(),
+        };
+        Ok(self
+            .entries()?
+            .into_iter()
+            .filter(|entry| entry.dest.starts_with(&gravepath))
+            .collect())
+    }
+
+    /// Return every entry in the record
+    pub fn entries(&self) -> Result<Vec<RecordItem>> {
+        self.record.entries().chain_err(|| "Failed to read record")
+    }
+
+    /// Return the grave of the last file to be buried, or of the last file
+    /// buried from under `cwd` if it is given
+    pub fn last_bury(&self, cwd: Option<&Path>) -> Result<PathBuf> {
+        match cwd {
+            Some(cwd) => get_last_bury(&self.record, &join_absolute(&self.path, cwd), "local"),
+            None => get_last_bury(&self.record, Path::new(""), "global"),
+        }
+        .chain_err(|| "Couldn't find a grave to exhume")
+    }
+
+    /// Permanently unlink each of `graves` and remove them from the record.
+    /// Graves that are already gone are only removed from the record.
+    pub fn purge(&self, graves: &[PathBuf]) -> Result<()> {
+        // Nothing is made just to remove nothing from it
+        if graves.is_empty() {
+            return Ok(());
+        }
+        let mut purged = Vec::new();
+        let mut failed = None;
+        for grave in graves {
+            self.forget_size(grave);
+            if symlink_exists(grave) && fs::remove_dir_all(grave).is_err() {
+                if let Err(e) = fs::remove_file(grave) {
+                    failed = Some(Error::from(e).chain_err(|| format!("Couldn't unlink {}", grave.display())));
+                    break;
+                }
+            }
+            purged.push(grave.clone());
+        }
+
+        // The graves that are gone are taken out of the record even if one
+        // of them couldn't be
+        self.record
+            .remove(&purged)
+            .chain_err(|| "Failed to remove purged files from record")?;
+
+        match failed {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// Permanently unlink the entire graveyard. Pinned graves are kept, along
+    /// with the record of them, unless `force` is set. A graveyard that doesn't
+    /// exist is left as it is.
+    pub fn decompose(&self, force: bool) -> Result<()> {
+        if !symlink_exists(&self.path) {
+            return Ok(());
+        }
+        // Nothing is kept when forced, so the record doesn't have to be read
+        let entries = if force { Vec::new() } else { self.entries()? };
+        if !entries.iter().any(|entry| entry.pinned) {
+            return fs::remove_dir_all(&self.path).chain_err(|| "Couldn't unlink graveyard");
+        }
+        let graves = entries
+            .into_iter()
+            .filter(|entry| !entry.pinned)
+            .map(|entry| entry.dest)
+            .collect::<Vec<_>>();
+        self.purge(&graves)
+    }
+}
+
+/// Return the path in the graveyard of the last file to be buried, only looking
+/// under `cwd` (a path in the graveyard) if `cwdp` is "local".
+/// As a side effect, any valid last files that are found in the record but
+/// not on the filesystem are removed from the record.
+fn get_last_bury(record: &Record, cwd: &Path, cwdp: &str) -> io::Result<PathBuf> {
+    let graves_to_exhume: &mut Vec<PathBuf> = &mut Vec::new();
+
+    for entry in record.entries()?.into_iter().rev() {
+        if cwdp == "local" {
+            // If local and doesn't contain path to cwd, continue
+            // Trying to exhume file that's not last bury globally, but locally
+            if !entry.dest.starts_with(cwd) {
+                    continue;
+            } else if symlink_exists(&entry.dest) {
+                if !graves_to_exhume.is_empty() {
+                    record.remove(graves_to_exhume)?;
+                }
+                return Ok(entry.dest);
+            } else {
+                // File is gone, mark the grave to be removed from the record
+                graves_to_exhume.push(entry.dest);
+            }
+        } else if cwdp == "global" {
+            // Check that the file is still in the graveyard.
+            // If it is, return the corresponding line.
+            if symlink_exists(&entry.dest) {
+                if !graves_to_exhume.is_empty() {
+                    record.remove(graves_to_exhume)?;
+                }
+                return Ok(entry.dest);
+            } else {
+                // File is gone, mark the grave to be removed from the record
+                graves_to_exhume.push(entry.dest);
+            }
+        }
+    }
+
+    if !graves_to_exhume.is_empty() {
+        record.remove(graves_to_exhume)?;
+    }
+    Err(io::Error::new(io::ErrorKind::NotFound, "But nobody came"))
+}
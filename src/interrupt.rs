@@ -0,0 +1,45 @@
+//! Stopping a burial cleanly on `SIGINT` or `SIGTERM`.
+//!
+//! Once [`catch`] is called, these signals only set a flag, which is checked
+//! while copying. A copy that is interrupted is removed, leaving the source
+//! where it was. The handlers are reset when the first signal arrives, so a
+//! second one kills `rip` right away.
+use std::{
+    io,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn handle(_: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+/// Catch `SIGINT` and `SIGTERM` instead of being killed by them
+pub fn catch() -> io::Result<()> {
+    for signal in [libc::SIGINT, libc::SIGTERM] {
+        unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = handle as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            action.sa_flags = libc::SA_RESETHAND;
+            libc::sigemptyset(&mut action.sa_mask);
+            if libc::sigaction(signal, &action, std::ptr::null_mut()) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Whether `SIGINT` or `SIGTERM` has been caught
+pub fn requested() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// Fail with [`io::ErrorKind::Interrupted`] if a signal has been caught
+pub(crate) fn check() -> io::Result<()> {
+    if requested() {
+        return Err(io::Error::new(io::ErrorKind::Interrupted, "interrupted"));
+    }
+    Ok(())
+}
@@ -0,0 +1,266 @@
+//! The journal keeps track of burials and exhumations while they happen, so
+//! that one that was interrupted, e.g., by `rip` being killed while copying a
+//! directory, can be completed or rolled back the next time `rip` runs.
+//!
+//! Each operation writes its intent to its own file in the journal directory,
+//! named after the pid of its process and a count of the operations that
+//! process started, e.g., `1234-0`, before it starts moving anything, and
+//! removes the file once the record has been updated. Intents left behind by
+//! processes that are no longer running are the ones that were interrupted.
+//! An intent that is taken over to be recovered is named the same way, along
+//! with its old name, e.g., `1234-1-987-0`.
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use crate::record::{path_bytes, RecordItem};
+
+/// Number of operations this process has started, which keeps the intents of
+/// operations that run at the same time, e.g., in different threads, apart
+static STARTED: AtomicU64 = AtomicU64::new(0);
+
+/// Whether files were being sent to or returned from the graveyard
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Operation {
+    Bury,
+    Unbury,
+}
+
+/// An operation that was started, as written to the journal
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Intent {
+    pub op: Operation,
+    /// Process that started the operation
+    pub pid: u32,
+    /// Which of the operations of the process this is. Intents written by
+    /// older versions only have a pid.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
+    /// Where the file is being moved from
+    #[serde(with = "path_bytes")]
+    pub from: PathBuf,
+    /// Where the file is being moved to
+    #[serde(with = "path_bytes")]
+    pub to: PathBuf,
+    /// The entry that is added to the record once a burial is done, or the one
+    /// that is removed from it once an exhumation is
+    pub entry: RecordItem,
+    /// Set once the file is completely at `to`, so that all that is left is
+    /// removing `from` and updating the record
+    #[serde(default)]
+    pub moved: bool,
+    /// Name of the interrupted intent that this one took over to recover it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub took_over: Option<String>,
+}
+
+impl Intent {
+    /// Start of an operation by this process
+    pub fn new<F, T>(op: Operation, from: F, to: T, entry: RecordItem) -> Self
+    where
+        F: Into<PathBuf>,
+        T: Into<PathBuf>,
+    {
+        Intent {
+            op,
+            pid: process::id(),
+            seq: Some(STARTED.fetch_add(1, Ordering::Relaxed)),
+            from: from.into(),
+            to: to.into(),
+            entry,
+            moved: false,
+            took_over: None,
+        }
+    }
+
+    /// Take over the interrupted intent `dead` as a new operation of this
+    /// process, so that it can be recovered
+    pub fn take_over(dead: &Intent) -> Self {
+        Intent {
+            pid: process::id(),
+            seq: Some(STARTED.fetch_add(1, Ordering::Relaxed)),
+            took_over: Some(dead.name()),
+            ..dead.clone()
+        }
+    }
+
+    /// Name of the file the intent is kept in
+    pub fn name(&self) -> String {
+        let mut name = self.pid.to_string();
+        if let Some(seq) = self.seq {
+            name.push_str(&format!("-{}", seq));
+        }
+        if let Some(old) = &self.took_over {
+            name.push_str(&format!("-{}", old));
+        }
+        name
+    }
+}
+
+/// What was done about an interrupted operation
+#[derive(Debug, Clone, PartialEq)]
+pub enum Recovery {
+    /// Everything had been moved, so the operation was completed
+    Completed(Intent),
+    /// The operation was undone, leaving the file where it was before
+    RolledBack(Intent),
+}
+
+/// Handle to the journal directory of a graveyard
+#[derive(Debug, Clone)]
+pub struct Journal {
+    dir: PathBuf,
+}
+
+impl Journal {
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        Journal { dir: dir.into() }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Write `intent`, replacing what was written for its operation before.
+    /// It is written to a temporary file and renamed into place, so that it is
+    /// never seen half-written.
+    pub fn write(&self, intent: &Intent) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        let path = self.dir.join(intent.name());
+        let tmp = self.dir.join(format!(".{}.tmp", intent.name()));
+
+        let mut f = fs::File::create(&tmp)?;
+        serde_json::to_writer(&mut f, intent)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        f.write_all(b"\n")?;
+        f.sync_all()?;
+        fs::rename(&tmp, &path)
+    }
+
+    /// Remove `intent`, once its operation is over
+    pub fn clear(&self, intent: &Intent) -> io::Result<()> {
+        match fs::remove_file(self.dir.join(intent.name())) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Return every intent in the journal, including those of operations that
+    /// are still going on. Files that can't be parsed are skipped.
+    pub fn intents(&self) -> io::Result<Vec<Intent>> {
+        let dir = match fs::read_dir(&self.dir) {
+            Ok(dir) => dir,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+
+        let mut intents = Vec::new();
+        for entry in dir {
+            let path = entry?.path();
+            let named = path
+                .file_name()
+                .and_then(|n| n.to_str())
+                .is_some_and(|n| n.split('-').all(|part| part.parse::<u64>().is_ok()));
+            if !named {
+                continue;
+            }
+            match fs::read(&path) {
+                Ok(contents) => {
+                    if let Ok(intent) = serde_json::from_slice::<Intent>(&contents) {
+                        intents.push(intent);
+                    }
+                },
+                // Cleared since the directory was read
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {},
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(intents)
+    }
+
+    /// Return the intents of processes that are no longer running, i.e., of
+    /// operations that were interrupted
+    pub fn interrupted(&self) -> io::Result<Vec<Intent>> {
+        Ok(self.intents()?.into_iter().filter(|intent| !running(intent.pid)).collect())
+    }
+}
+
+/// Whether the process `pid` is still running
+fn running(pid: u32) -> bool {
+    if pid == process::id() {
+        return true;
+    }
+    // Signal 0 only checks whether the process exists
+    let signalled = unsafe { libc::kill(pid as libc::pid_t, 0) } == 0;
+    signalled || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::DateTime;
+
+    /// A journal in a directory of its own, which is removed when dropped
+    struct Scratch(Journal);
+
+    impl Scratch {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("rip-journal-{}-{}", name, process::id()));
+            fs::remove_dir_all(&dir).ok();
+            Scratch(Journal::new(dir))
+        }
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            fs::remove_dir_all(self.0.path()).ok();
+        }
+    }
+
+    fn intent(from: &str) -> Intent {
+        let to = Path::new("/tmp/graveyard").join(from.trim_start_matches('/'));
+        Intent::new(Operation::Bury, from, &to, RecordItem {
+            deleted: DateTime::parse_from_rfc3339("2024-01-02T03:04:05+01:00").unwrap(),
+            orig: PathBuf::from(from),
+            dest: to.clone(),
+            placeholder: false,
+            digest: None,
+            pinned: false,
+        })
+    }
+
+    #[test]
+    fn keeps_operations_of_one_process_apart() {
+        let scratch = Scratch::new("apart");
+        let (first, second) = (intent("/home/jack/a"), intent("/home/jack/b"));
+        scratch.0.write(&first).unwrap();
+        scratch.0.write(&second).unwrap();
+
+        let mut froms = scratch.0.intents().unwrap().into_iter().map(|i| i.from).collect::<Vec<_>>();
+        froms.sort();
+        assert_eq!(froms, [first.from.clone(), second.from.clone()]);
+
+        scratch.0.clear(&first).unwrap();
+        assert_eq!(scratch.0.intents().unwrap(), [second]);
+    }
+
+    #[test]
+    fn takes_over_intents_without_a_count() {
+        let scratch = Scratch::new("legacy");
+        let dead = Intent { pid: 1, seq: None, ..intent("/home/jack/a") };
+        scratch.0.write(&dead).unwrap();
+        assert!(scratch.0.path().join("1").exists());
+
+        let taken = Intent::take_over(&dead);
+        scratch.0.write(&taken).unwrap();
+        scratch.0.clear(&dead).unwrap();
+        assert!(taken.name().ends_with("-1"));
+        assert_eq!(scratch.0.intents().unwrap(), [taken]);
+    }
+}
@@ -0,0 +1,104 @@
+//! rip: a safe and ergonomic alternative to rm
+//!
+//! The binary is a thin client over this library. Other tools can use the
+//! [`Graveyard`] type to send files to the graveyard, list what is buried
+//! there, and bring it back.
+//!
+//! ```no_run
+//! use rip::Graveyard;
+//!
+//! let graveyard = Graveyard::resolve(None);
+//! let burial = graveyard.bury("/tmp/some-file").unwrap();
+//! graveyard.unbury(&[burial.grave]).unwrap();
+//! ```
+#![allow(dead_code)]
+#[macro_use]
+extern crate error_chain;
+extern crate globwalk;
+extern crate walkdir;
+
+pub mod errors {
+    error_chain! {
+        foreign_links {
+           IoError(::std::io::Error);
+          }
+
+        errors {
+            IncompleteCopy(source: std::path::PathBuf, problems: Vec<String>) {
+                description("couldn't copy everything, so the source was left in place")
+                display(
+                    "Couldn't copy all of {}, so it was left in place:\n  {}",
+                    source.display(),
+                    problems.join("\n  ")
+                )
+            }
+
+            Interrupted(source: std::path::PathBuf) {
+                description("interrupted, so the source was left in place")
+                display("Interrupted, so {} was left where it was", source.display())
+            }
+
+            NoRoom(source: std::path::PathBuf, shortfall: crate::quota::Shortfall) {
+                description("not enough room in the graveyard")
+                display(
+                    "Not enough room to bury {}: it needs {}, but only {} is left {}",
+                    source.display(),
+                    crate::util::humanize_bytes(shortfall.needed),
+                    crate::util::humanize_bytes(shortfall.available),
+                    match shortfall.limit {
+                        crate::quota::Limit::MaxSize => "under the size limit of the graveyard",
+                        crate::quota::Limit::MinFree => "on the filesystem of the graveyard",
+                    }
+                )
+            }
+
+            Taken(grave: std::path::PathBuf, path: std::path::PathBuf) {
+                description("the path to unbury to is taken")
+                display("{} already exists, so {} was left in the graveyard", path.display(), grave.display())
+            }
+
+            NoDevice(source: std::path::PathBuf, devices: Vec<std::path::PathBuf>) {
+                description("device nodes can't be made without privileges")
+                display(
+                    "Couldn't make the device nodes in {} without privileges, so it was left in place:\n  {}",
+                    source.display(),
+                    devices.iter().map(|d| d.display().to_string()).collect::<Vec<_>>().join("\n  ")
+                )
+            }
+            PartialRemoval(source: std::path::PathBuf) {
+                description("copied everything, but couldn't remove all of the source")
+                display("Copied all of {}, but couldn't remove all of it", source.display())
+            }
+        }
+    }
+}
+
+mod bury;
+pub mod checksum;
+pub mod config;
+mod copy;
+pub mod doctor;
+pub mod gc;
+pub mod graveyard;
+pub mod interrupt;
+pub mod journal;
+pub mod lock;
+pub mod quota;
+pub mod record;
+pub mod util;
+
+pub use config::Config;
+pub use doctor::Problem;
+pub use gc::Retention;
+pub use graveyard::{Burial, Conflict, Dropped, Exhumed, Graveyard, Outcome, Verdict};
+pub use quota::{Limits, Shortfall};
+pub use record::{Record, RecordItem};
+
+/// Base of the default graveyard when neither `$GRAVEYARD` nor `$XDG_DATA_HOME` are set
+pub const GRAVEYARD: &str = "/tmp/graveyard";
+/// Name of the file inside the graveyard that keeps track of each burial
+pub const RECORD: &str = ".record";
+/// Name of the directory inside the graveyard that holds operations in progress
+pub const JOURNAL: &str = ".journal";
+/// Name of the file inside the graveyard that is locked while changing it
+pub const LOCK: &str = ".lock";
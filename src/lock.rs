@@ -0,0 +1,58 @@
+//! Advisory locking of the graveyard, so that `rip`s running at the same time
+//! don't lose each other's changes to the record or bury files to the same grave.
+//!
+//! The lock is a `flock(2)` on the `.lock` file at the top of the graveyard.
+//! It is released when the [`Lock`] is dropped, or when the process exits.
+use std::{
+    fs, io,
+    os::unix::io::AsRawFd,
+    path::Path,
+};
+
+use crate::interrupt;
+
+/// A held lock on a graveyard
+#[derive(Debug)]
+pub struct Lock {
+    file: fs::File,
+}
+
+impl Lock {
+    /// Wait for the lock at `path`, so that nothing else can hold it at the
+    /// same time. The file and its parent directory are created if needed.
+    pub fn exclusive<P: AsRef<Path>>(path: P) -> io::Result<Lock> {
+        let path = path.as_ref();
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        Lock::acquire(path, libc::LOCK_EX)
+    }
+
+    /// Wait for the lock at `path`, which may be shared with other readers.
+    /// Returns `None` if there is no graveyard to lock yet.
+    pub fn shared<P: AsRef<Path>>(path: P) -> io::Result<Option<Lock>> {
+        match Lock::acquire(path.as_ref(), libc::LOCK_SH) {
+            Ok(lock) => Ok(Some(lock)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn acquire(path: &Path, operation: libc::c_int) -> io::Result<Lock> {
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        loop {
+            if unsafe { libc::flock(file.as_raw_fd(), operation) } == 0 {
+                return Ok(Lock { file });
+            }
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::Interrupted || interrupt::requested() {
+                return Err(e);
+            }
+        }
+    }
+}
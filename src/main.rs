@@ -3,12 +3,12 @@ extern crate clap;
 extern crate core;
 #[macro_use]
 extern crate error_chain;
-extern crate time;
-extern crate walkdir;
 extern crate globwalk;
+extern crate rip;
 extern crate tabwriter;
+extern crate walkdir;
 
-use clap::{crate_authors, crate_version, App, AppSettings, Arg};
+use clap::{crate_authors, crate_version, App, AppSettings, Arg, ArgGroup, ArgMatches};
 use clap_generate::{
     generators::*,
     generate,
@@ -16,8 +16,10 @@ use clap_generate::{
 };
 
 use std::{
-    io::{BufRead, BufReader, Read, Write, Cursor},
-    os::unix::fs::{FileTypeExt, PermissionsExt},
+    collections::{BTreeMap, HashSet},
+    ffi::{OsStr, OsString},
+    io::{BufRead, BufReader, Write, Cursor},
+    os::unix::ffi::OsStrExt,
     path::{Path, PathBuf},
     env,
     fs,
@@ -29,11 +31,23 @@ use globwalk::{GlobWalker, GlobWalkerBuilder};
 use chrono::offset::Local;
 use chrono::DateTime;
 
-// use thiserror::Error;
-// use anyhow::{Context, Result};
+use rip::{
+    interrupt,
+    journal::{Operation, Recovery},
+    quota::Limit,
+    util::{
+        cutoff, file_type, humanize_bytes, join_absolute, parse_duration, parse_size, prompt_choice,
+        prompt_yes, symlink_exists, usage, walk, Entry, Usage,
+    },
+    Config, Conflict, Dropped, Exhumed, Graveyard, Outcome, Problem, Verdict, JOURNAL, LOCK, RECORD,
+};
 
 mod errors {
     error_chain! {
+        links {
+            Rip(rip::errors::Error, rip::errors::ErrorKind);
+        }
+
         foreign_links {
            IoError(::std::io::Error);
           }
@@ -59,7 +73,6 @@ use errors::*;
 use colored::*;
 
 mod comp_helper;
-include!("util.rs");
 
 macro_rules! fmt_exp {
     ($a:expr,$b:ident) => {
@@ -86,19 +99,10 @@ macro_rules! verbosed {
     };
 }
 
-const GRAVEYARD: &str = "/tmp/graveyard";
-const RECORD: &str = ".record";
 const LINES_TO_INSPECT: usize = 6;
 const FILES_TO_INSPECT: usize = 6;
-const BIG_FILE_THRESHOLD: u64 = 500000000; // 500 MB
 const DEFAULT_MAX_DEPTH: usize = 10; // 10 because $HOME/.local/share/graveyard is already pretty deep
 
-struct RecordItem<'a> {
-    _time: &'a str,
-    orig: &'a Path,
-    dest: &'a Path,
-}
-
 #[allow(clippy::enum_variant_names)]
 enum Shell {
     Bash,
@@ -123,7 +127,8 @@ impl std::str::FromStr for Shell {
 }
 
 fn main() {
-    if let Err(ref e) = run() {
+    let result = run();
+    if let Err(ref e) = result {
         let stderr = &mut ::std::io::stderr();
         let errmsg = "Error writing to stderr";
 
@@ -136,38 +141,159 @@ fn main() {
         if let Some(backtrace) = e.backtrace() {
             writeln!(stderr, "backtrace: {:?}", backtrace).expect(errmsg);
         }
+    }
 
+    // Exit the way a process killed by the signal would
+    if interrupt::requested() {
+        ::std::process::exit(130);
+    }
+    if result.is_err() {
         ::std::process::exit(1);
     }
 }
 
 fn run() -> Result<()> {
     let matches = &cli_rip().get_matches();
+
+    // Stop cleanly on Ctrl-C, leaving what was being copied where it was
+    interrupt::catch().chain_err(|| "Couldn't set up signal handlers")?;
     let nocolor: bool = matches.is_present("nocolor");
     let verbose: bool = matches.is_present("verbose");
+    let config = Config::load()?;
 
-    let graveyard: &PathBuf = &{
-        if let Some(flag) = matches.value_of("graveyard") {
-            flag.to_owned()
-        } else if let Ok(env) = env::var("GRAVEYARD") {
-            env
-        } else if let Ok(mut env) = env::var("XDG_DATA_HOME") {
-            if !env.ends_with(std::path::MAIN_SEPARATOR) {
-                env.push(std::path::MAIN_SEPARATOR);
+    let conflict = match matches.value_of("conflict") {
+        Some(conflict) => conflict.parse()?,
+        None => config.unbury.conflict.unwrap_or(Conflict::Rename),
+    };
+    let graveyard = &Graveyard::resolve(matches.value_of("graveyard"))
+        .checksum(matches.is_present("checksum"))
+        .limits(config.quota.limits()?)
+        .conflict(conflict);
+
+    if verbose { verbose!("graveyard", graveyard.path().display()); }
+
+    // Complete or roll back whatever an earlier rip was doing when it was killed
+    match graveyard.recover() {
+        Ok(recovered) => for recovery in recovered {
+            match recovery {
+                Recovery::Completed(intent) => match intent.op {
+                    Operation::Bury => println!("Finished burying {}", fmt_exp!(intent.from, magenta)),
+                    Operation::Unbury => println!("Finished returning {}", fmt_exp!(intent.to, red)),
+                },
+                Recovery::RolledBack(intent) => match intent.op {
+                    Operation::Bury => println!("Left {} in place after an interrupted burial",
+                        fmt_exp!(intent.from, magenta)),
+                    Operation::Unbury => println!("Left {} in the graveyard after an interrupted unbury",
+                        fmt_exp!(intent.from, magenta)),
+                },
             }
-            env.push_str("graveyard");
-            env
-        } else {
-            format!("{}-{}", GRAVEYARD, get_user())
-        }
+        },
+        Err(e) => eprintln!("{}: {}", "warning".yellow().bold(), e),
     }
-    .into();
 
-    if verbose { verbose!("graveyard", graveyard.display()); }
+    let cwd: PathBuf = env::current_dir().chain_err(|| "Failed to get current dir")?;
 
+    // == DECOMPOSE ==
     if matches.is_present("decompose") {
-        if prompt_yes("Really unlink the entire graveyard?") {
-            // let dir = fs::read_dir(graveyard).chain_err(|| "Couldn't read dir")?;
+        if !symlink_exists(graveyard.path()) {
+            println!("Nothing to decompose");
+            return Ok(());
+        }
+        let force = matches.is_present("force");
+        // Scoped like -s: -l for what was buried from under the current
+        // directory, and --older-than for what was buried long enough ago
+        let scoped = matches.is_present("local") || matches.is_present("older-than");
+        // Without a record there is nothing to scope by or to keep pinned, but
+        // the whole graveyard can still be unlinked
+        let (mut entries, unreadable) = match graveyard.list(if matches.is_present("local") { Some(&cwd) } else { None }) {
+            Ok(entries) => (entries, false),
+            Err(e) if !scoped => {
+                let reason = e.iter().map(ToString::to_string).collect::<Vec<_>>().join(": ");
+                eprintln!("{}: {}, so nothing can be kept", "warning".yellow().bold(), reason);
+                (Vec::new(), true)
+            },
+            Err(e) => return Err(e.into()),
+        };
+        if let Some(age) = matches.value_of("older-than") {
+            let cutoff = parse_cutoff(age)?;
+            entries.retain(|entry| entry.deleted.timestamp() < cutoff);
+        }
+        let (entries, pinned): (Vec<_>, Vec<_>) = entries.into_iter()
+            .partition(|entry| force || !entry.pinned);
+        // The whole graveyard is removed unless something in it is kept, along
+        // with anything in it that isn't in the record
+        let whole = !scoped && pinned.is_empty();
+        let orphans = if unreadable {
+            unrecorded(graveyard)?
+        } else if whole {
+            graveyard.examine()?
+                .into_iter()
+                .filter_map(|problem| match problem {
+                    Problem::Orphan { grave, orig } => Some((orig, grave)),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        } else {
+            Vec::new()
+        };
+        let doomed = entries.iter()
+            .map(|entry| (entry.orig.clone(), entry.dest.clone()))
+            .chain(orphans.iter().cloned())
+            .collect::<Vec<_>>();
+        if doomed.is_empty() {
+            println!("Nothing to decompose");
+            return Ok(());
+        }
+
+        // Sum up what will be unlinked, by the top-level directory it was
+        // buried from, below the one that all of it was buried from
+        let root = common_root(doomed.iter().map(|(orig, _)| orig.parent().unwrap_or(orig)));
+        let mut summary: BTreeMap<PathBuf, (usize, Usage)> = BTreeMap::new();
+        for (orig, grave) in &doomed {
+            let used = usage(grave);
+            // Graves that were right in the root are summed up under it
+            let mut components = orig.strip_prefix(&root).unwrap_or(orig).components();
+            let top = match (components.next(), components.next()) {
+                (Some(first), Some(_)) => root.join(first),
+                _ => root.clone(),
+            };
+            let dir = summary.entry(top).or_default();
+            dir.0 += 1;
+            dir.1.bytes += used.bytes;
+            dir.1.files += used.files;
+        }
+        {
+            let stdout = io::stdout();
+            let mut tab_handle = tabwriter::TabWriter::new(io::BufWriter::new(stdout.lock()));
+            let row = |graves: usize, usage: &Usage| (
+                format!("{} graves", graves).green().bold(),
+                format!("{} files", usage.files).cyan().bold(),
+                humanize_bytes(usage.bytes).magenta().bold(),
+            );
+            for (dir, (graves, usage)) in &summary {
+                let (graves, files, size) = row(*graves, usage);
+                writeln!(tab_handle, "{}\t{}\t{}\t{}", graves, files, size, fmt_exp!(dir, yellow))?;
+            }
+            let total = summary.values().fold(Usage::default(), |total, (_, usage)| Usage {
+                bytes: total.bytes + usage.bytes,
+                files: total.files + usage.files,
+            });
+            let (graves, files, size) = row(doomed.len(), &total);
+            writeln!(tab_handle, "{}\t{}\t{}\t{}", graves, files, size, "total".bold())?;
+            tab_handle.flush()?;
+        }
+        if !orphans.is_empty() {
+            println!("{} of these graves aren't in the record", orphans.len());
+        }
+
+        let question = if scoped {
+            format!("Really unlink these {} graves?", entries.len())
+        } else if !pinned.is_empty() {
+            format!("Really unlink the entire graveyard, except for {} pinned graves?", pinned.len())
+        } else {
+            "Really unlink the entire graveyard?".to_string()
+        };
+        if prompt_yes(question) {
             if verbose {
                 let stdout = io::stdout();
                 let std_lock = stdout.lock();
@@ -177,162 +303,321 @@ fn run() -> Result<()> {
                 writeln!(tab_handle, "{}\t{}", "File".cyan().bold(), "Type".bright_red().bold())?;
                 writeln!(tab_handle, "{}\t{}", "----".cyan().bold(), "----".bright_red().bold())?;
 
-                let mut f = fs::File::open(&graveyard.join(RECORD))?;
-                let mut contents = String::new();
-                f.read_to_string(&mut contents)?;
-
-                for entry in contents.lines().map(record_entry) {
+                for (orig, grave) in &doomed {
                     writeln!(tab_handle, "{}\t{}",
-                        fmt_exp!(entry.orig, cyan),
-                        file_type(
-                            &join_absolute(
-                                &graveyard,
-                                PathBuf::from(entry.orig),
-                            )
-                        ).bright_red().bold(),
+                        fmt_exp!(orig, cyan),
+                        file_type(grave).bright_red().bold(),
                     )?;
                 }
                 tab_handle.flush()?;
             }
-            fs::remove_dir_all(graveyard).chain_err(|| "Couldn't unlink graveyard")?;
+            if scoped {
+                graveyard.purge(&entries.into_iter().map(|entry| entry.dest).collect::<Vec<_>>())?;
+            } else {
+                graveyard.decompose(force || unreadable)?;
+            }
         }
         return Ok(());
     }
 
-    let record: &Path = &graveyard.join(RECORD);
-    let cwd: PathBuf = env::current_dir().chain_err(|| "Failed to get current dir")?;
+    // == VERIFY ==
+    if let Some(matches) = matches.subcommand_matches("verify") {
+        let entries = graveyard.entries()?;
+        // Check the graves that were asked for, or every grave with a checksum
+        let entries = if let Some(graves) = matches.values_of_os("GRAVE") {
+            let graves = resolve_graves(graveyard, &cwd, matches.is_present("all"), graves)?;
+            entries.into_iter().filter(|entry| graves.contains(&entry.dest)).collect()
+        } else {
+            entries.into_iter().filter(|entry| entry.digest.is_some()).collect::<Vec<_>>()
+        };
 
-    // == UNBURY ==
-    if let Some(t) = matches.values_of("unbury") {
-        // Maybe a cleaner way? This is to detect if a glob is given (*glob, **glob)
-        let glob = t.clone().next()
-            .unwrap_or("None")
-            .contains('*');
-
-        if verbose { verbose!("globbing", glob); }
-
-        // Vector to hold the grave path of items we want to unbury.
-        // This will be used to determine which items to remove from the
-        // record following the unbury.
-        // Initialize it with the targets passed to -r
-        let graves_to_exhume = &mut {
-            if glob {
-                let max_d = if let Some(max_depth) = matches.value_of("max-depth") {
-                    max_depth.parse::<usize>().unwrap()
-                } else {
-                    DEFAULT_MAX_DEPTH
-                };
-                if verbose { verbose!("max depth", max_d); }
-
-                if matches.is_present("local") {
-                    glob_walk(
-                        t.clone().next().unwrap(),
-                        join_absolute(&graveyard, &cwd),
-                        max_d
-                    )
-                } else {
-                    glob_walk(
-                        t.clone().next().unwrap(),
-                        &graveyard,
-                        max_d
-                    )
-                }
-            } else {
-                // Match files in local directory
-                if matches.is_present("local") {
-                    t.clone().map(|file|
-                        join_absolute(
-                            join_absolute(graveyard, &cwd),
-                            PathBuf::from(file)
-                        )
-                    ).collect::<Vec<PathBuf>>()
-                } else if matches.value_of("unbury")
-                    .unwrap_or("None")
-                    .to_string()
-                    .contains(graveyard.to_str().unwrap())
-                {
-                    // Full path given (including graveyard)
-                    t.clone().map(PathBuf::from).collect::<Vec<PathBuf>>()
-                } else {
-                    // Full path given (excluding graveyard, i.e., starting from $HOME)
-                    t.clone().map(|file|
-                        join_absolute(
-                            graveyard,
-                            PathBuf::from(file)
-                        )
-                    ).collect::<Vec<PathBuf>>()
-                }
+        let stdout = io::stdout();
+        let mut tab_handle = tabwriter::TabWriter::new(io::BufWriter::new(stdout.lock()));
+        let mut failed = 0;
+
+        for entry in &entries {
+            let verdict = graveyard.verify(entry)?;
+            if verdict == Verdict::Changed || verdict == Verdict::Missing {
+                failed += 1;
             }
-        };
+            let verdict = match verdict {
+                Verdict::Intact => "ok".green().bold(),
+                Verdict::Changed => "changed".red().bold(),
+                Verdict::Missing => "missing".red().bold(),
+                Verdict::Unrecorded => "no checksum".yellow().bold(),
+            };
+            writeln!(tab_handle, "{}\t{}",
+                verdict,
+                fmt_exp!(Path::new("/").join(shorten(graveyard, &entry.dest)), cyan)
+            )?;
+        }
+        tab_handle.flush()?;
+
+        if failed > 0 {
+            bail!("{} of {} graves failed verification", failed, entries.len());
+        }
+        return Ok(());
+    }
+
+    // == DOCTOR ==
+    if let Some(matches) = matches.subcommand_matches("doctor") {
+        let problems = graveyard.examine()?;
 
-        if verbose { verbosed!("exhumed cli matches", graves_to_exhume); }
-        // If -s is also passed, push all files found by seance onto
-        // the graves_to_exhume.
-        if matches.is_present("seance") {
-            if let Ok(f) = fs::File::open(record) {
-                let gravepath = join_absolute(graveyard, cwd).to_string_lossy().into_owned();
-                for grave in seance(f, gravepath) {
-                    graves_to_exhume.push(grave);
+        // Written in a block so the table is out before the summary
+        {
+            let stdout = io::stdout();
+            let mut tab_handle = tabwriter::TabWriter::new(io::BufWriter::new(stdout.lock()));
+            let shown = |grave: &Path| Path::new("/").join(shorten(graveyard, grave));
+
+            for problem in &problems {
+                match problem {
+                    Problem::Orphan { grave, orig } => writeln!(tab_handle, "{}\t{}\tnot in the record, buried from {}",
+                        "orphan".yellow().bold(),
+                        fmt_exp!(shown(grave), cyan),
+                        fmt_exp!(orig, magenta)
+                    )?,
+                    Problem::Dangling(entry) => writeln!(tab_handle, "{}\t{}\tno longer in the graveyard",
+                        "dangling".red().bold(),
+                        fmt_exp!(shown(&entry.dest), cyan)
+                    )?,
+                    Problem::Duplicate(entry) => writeln!(tab_handle, "{}\t{}\trecorded more than once",
+                        "duplicate".red().bold(),
+                        fmt_exp!(shown(&entry.dest), cyan)
+                    )?,
+                    Problem::Outside(entry) => writeln!(tab_handle, "{}\t{}\tnot in the graveyard",
+                        "outside".red().bold(),
+                        fmt_exp!(entry.dest, cyan)
+                    )?,
+                    Problem::Malformed(line) => writeln!(tab_handle, "{}\t{}\tcan't be read",
+                        "malformed".red().bold(),
+                        String::from_utf8_lossy(line).cyan().bold()
+                    )?,
                 }
             }
-            if verbose { verbosed!("exhumed after seance", graves_to_exhume); }
+            tab_handle.flush()?;
         }
 
-        // Otherwise, add the last deleted file, globally or locally
-        if graves_to_exhume.is_empty() {
-            let ncwd = env::current_dir().chain_err(|| "Failed to get current dir")?;
-            if matches.is_present("local") {
-                if let Ok(s) = get_last_bury(record, &ncwd, "local") {
-                    if verbose { verbose!("exhuming", "locally"); }
-                    graves_to_exhume.push(s);
-                }
-            } else {
-                if verbose { verbose!("exhuming", "globally"); }
-                if let Ok(s) = get_last_bury(record, &ncwd, "global") {
-                    graves_to_exhume.push(s);
+        if problems.is_empty() {
+            println!("No problems found in {}", fmt_exp!(graveyard.path(), magenta));
+        } else if matches.is_present("repair") {
+            graveyard.repair(&problems)?;
+            println!("Repaired {} problems", problems.len());
+        } else {
+            println!("Found {} problems, run 'rip doctor --repair' to repair them", problems.len());
+        }
+        return Ok(());
+    }
+
+    // == PIN ==
+    if let Some(matches) = matches.subcommand_matches("pin") {
+        let graves = resolve_graves(
+            graveyard,
+            &cwd,
+            matches.is_present("all"),
+            matches.values_of_os("GRAVE").unwrap()
+        )?;
+        let pinned = !matches.is_present("remove");
+        for entry in graveyard.pin(&graves, pinned)? {
+            println!("{} {}", if pinned { "Pinned" } else { "Unpinned" }, fmt_exp!(entry.orig, magenta));
+        }
+        return Ok(());
+    }
+
+    // == GC ==
+    if let Some(matches) = matches.subcommand_matches("gc") {
+        // Limits given on the command line take the place of the configured ones
+        let mut retention = config.gc.retention()?;
+        if let Some(age) = matches.value_of("older-than") {
+            parse_cutoff(age)?;
+            retention.older_than = parse_duration(age);
+        }
+        if let Some(size) = matches.value_of("max-size") {
+            retention.max_size = Some(parse_size(size)
+                .ok_or_else(|| format!("Invalid size: {}", size))?);
+        }
+        retention.force = matches.is_present("force");
+        if retention.is_empty() {
+            bail!("Nothing to collect: give --older-than or --max-size, or set them in the config");
+        }
+
+        let dry_run = matches.is_present("dry-run");
+        let expired = if dry_run {
+            graveyard.expired(&retention)?
+        } else {
+            graveyard.gc(&retention)?
+        };
+
+        for (entry, size) in &expired {
+            println!("{} {} ({}, buried {})",
+                if dry_run { "Would expire" } else { "Expired" },
+                fmt_exp!(entry.orig, magenta),
+                humanize_bytes(*size).green().bold(),
+                entry.deleted.with_timezone(&Local).format("%Y-%m-%d %T")
+            );
+        }
+        println!("{} {} graves, {}",
+            if dry_run { "Would free" } else { "Freed" },
+            expired.len(),
+            humanize_bytes(expired.iter().map(|(_, size)| size).sum()).green().bold()
+        );
+        return Ok(());
+    }
+
+    // == TREE ==
+    if let Some(t) = matches.values_of_os("tree") {
+        let mut graves = select_graves(matches, &t.collect::<Vec<_>>(), graveyard, &cwd, verbose)?;
+        if graves.is_empty() && !matches.is_present("older-than") {
+            graves.extend(last_grave(matches, graveyard, &cwd, verbose));
+        }
+
+        // Only graves, and paths inside of them, are listed
+        let entries = graveyard.entries()?;
+        graves.retain(|grave| {
+            symlink_exists(grave) && entries.iter().any(|entry| grave.starts_with(&entry.dest))
+        });
+        if graves.is_empty() {
+            bail!("No graves to list");
+        }
+
+        {
+            let stdout = io::stdout();
+            let mut tab_handle = tabwriter::TabWriter::new(io::BufWriter::new(stdout.lock()));
+            for grave in &graves {
+                for (entry, size) in tree(grave) {
+                    let otype = file_type(&entry.path);
+                    let size = humanize_bytes(size);
+                    // The grave is shown by its full path, and what is in it by
+                    // name, indented by how deep it is
+                    let name = if entry.depth == 0 {
+                        if matches.is_present("fullpath") {
+                            Path::new("$GRAVEYARD").join(shorten(graveyard, grave))
+                        } else {
+                            Path::new("/").join(shorten(graveyard, grave))
+                        }
+                    } else {
+                        let mut name = OsString::from("  ".repeat(entry.depth));
+                        name.push(entry.file_name());
+                        PathBuf::from(name)
+                    };
+
+                    if nocolor {
+                        write!(tab_handle, "{}\t{}\t", otype, size)?;
+                        tab_handle.write_all(name.as_os_str().as_bytes())?;
+                        writeln!(tab_handle)?;
+                    } else {
+                        writeln!(tab_handle, "{}\t{}\t{}",
+                            otype.bright_red().bold(),
+                            size.green().bold(),
+                            if entry.depth == 0 { fmt_exp!(name, yellow) } else { fmt_exp!(name, cyan) }
+                        )?;
+                    }
                 }
             }
-            if verbose { verbosed!("exhumed last bury", graves_to_exhume); }
+            tab_handle.flush()?;
         }
+        return Ok(());
+    }
 
-        // Go through the graveyard and exhume all the graves
-        let f = fs::File::open(record).chain_err(|| "Couldn't read the record")?;
-        for line in lines_of_graves(f, graves_to_exhume) {
-            let entry: RecordItem = record_entry(&line);
-            let orig: &Path = &{
-                if symlink_exists(entry.orig) {
-                    rename_grave(entry.orig)
-                } else {
-                    PathBuf::from(entry.orig)
-                }
-            };
-            bury(entry.dest, orig).chain_err(|| {
-                format!(
-                    "Unbury failed: couldn't copy files from {} to {}",
-                    fmt_exp!(entry.dest, magenta),
-                    fmt_exp!(orig, red)
-                )
-            })?;
-            // Replaces value of $GRAVEYARD with the variable name because it is so long
+    // == PURGE ==
+    if let Some(t) = matches.values_of_os("purge") {
+        let targets = t.collect::<Vec<_>>();
+        if targets.is_empty()
+            && !["seance", "index", "older-than"].iter().any(|arg| matches.is_present(arg))
+        {
+            bail!("Nothing to purge: give some graves, or use -s, --index or --older-than");
+        }
+        let graves = select_graves(matches, &targets, graveyard, &cwd, verbose)?;
+
+        // Only recorded graves are purged, never other files in the graveyard
+        let entries = graveyard.entries()?
+            .into_iter()
+            .filter(|entry| graves.contains(&entry.dest))
+            .collect::<Vec<_>>();
+        let dry_run = matches.is_present("dry-run");
+        if !dry_run {
+            graveyard.purge(&entries.iter().map(|entry| entry.dest.clone()).collect::<Vec<_>>())?;
+        }
+
+        for entry in &entries {
+            let verb = if dry_run { "Would purge" } else { "Purged" };
             if matches.is_present("fullpath") {
-                println!("Returned {} to {}",
-                    entry.dest.display()
-                        .to_string()
-                        .replace(graveyard.to_str().unwrap(), "$GRAVEYARD")
-                        .magenta()
-                        .bold(),
-                    fmt_exp!(orig, red)
-                );
+                println!("{} {}", verb, fmt_exp!(
+                    Path::new("$GRAVEYARD").join(shorten(graveyard, &entry.dest)),
+                    magenta
+                ));
             } else {
-                println!("Returned {}", fmt_exp!(orig, red));
+                println!("{} {}", verb, fmt_exp!(entry.orig, magenta));
             }
         }
+        return Ok(());
+    }
 
-        // Reopen the record and then delete lines corresponding to exhumed graves
-        if let Err(e) = fs::File::open(record)
-            .and_then(|f| delete_lines_from_record(f, record, graves_to_exhume))
-        {
-            bail!("Failed to remove unburied files from record: {}", e);
+    // == UNBURY ==
+    if let Some(t) = matches.values_of_os("unbury") {
+        let graves_to_exhume = &mut select_graves(matches, &t.collect::<Vec<_>>(), graveyard, &cwd, verbose)?;
+
+        // Otherwise, add the last deleted file, globally or locally
+        if graves_to_exhume.is_empty() && !matches.is_present("older-than") {
+            graves_to_exhume.extend(last_grave(matches, graveyard, &cwd, verbose));
+        }
+
+        // Go through the graveyard and exhume all the graves, either back to
+        // where they were or under the directory given to --to. They are
+        // exhumed one at a time, so that each is reported even if a later
+        // one fails.
+        let to = matches.value_of_os("to");
+        for grave in graves_to_exhume.iter() {
+            let exhume = |graveyard: &Graveyard| if let Some(dir) = to {
+                graveyard.unbury_to(std::slice::from_ref(grave), dir, matches.is_present("parents"))
+            } else {
+                graveyard.unbury(std::slice::from_ref(grave))
+            };
+            let exhumed = match exhume(graveyard) {
+                // Ask what to do, and try again with the answer
+                Err(rip::errors::Error(rip::errors::ErrorKind::Taken(_, path), _))
+                    if conflict == Conflict::Prompt =>
+                {
+                    let question = format!(
+                        "{} already exists. Rename the returned file, overwrite it (burying what is there), skip it, or fail?",
+                        path.display()
+                    );
+                    let conflict = match prompt_choice(question, "rosF") {
+                        'r' => Conflict::Rename,
+                        'o' => Conflict::Overwrite,
+                        's' => Conflict::Skip,
+                        _ => Conflict::Fail,
+                    };
+                    exhume(&graveyard.clone().conflict(conflict))?
+                },
+                exhumed => exhumed?,
+            };
+            for Exhumed { grave, outcome, dropped } in exhumed {
+                report_dropped(&dropped, verbose);
+                // Replaces value of $GRAVEYARD with the variable name because it is so long
+                let shown = if matches.is_present("fullpath") {
+                    fmt_exp!(Path::new("$GRAVEYARD").join(shorten(graveyard, &grave)), magenta)
+                } else {
+                    fmt_exp!(Path::new("/").join(shorten(graveyard, &grave)), magenta)
+                };
+                match outcome {
+                    Outcome::Restored(orig) if to.is_none() && !matches.is_present("fullpath") => {
+                        println!("Returned {}", fmt_exp!(orig, red));
+                    },
+                    Outcome::Restored(orig) => println!("Returned {} to {}", shown, fmt_exp!(orig, red)),
+                    Outcome::Renamed(orig) => println!("Returned {} to {}, since its path is taken",
+                        shown,
+                        fmt_exp!(orig, red)
+                    ),
+                    Outcome::Replaced { path, .. } => println!("Returned {} to {}, burying what was there",
+                        shown,
+                        fmt_exp!(path, red)
+                    ),
+                    Outcome::Skipped(path) => println!("Skipped {}, since {} is taken",
+                        shown,
+                        fmt_exp!(path, red)
+                    ),
+                }
+            }
         }
         return Ok(());
     }
@@ -340,94 +625,98 @@ fn run() -> Result<()> {
     // == SEANCE ==
     if matches.is_present("seance") {
         // If all is passed, list the entire graveyard
-        let gravepath = if matches.is_present("all") {
-            PathBuf::from(graveyard)
+        let entries = if matches.is_present("all") {
+            graveyard.list(None)?
         } else {
-            join_absolute(graveyard, cwd)
+            graveyard.list(Some(&cwd))?
+        };
+
+        // Path that is displayed for each grave
+        let shown = |grave: &Path| -> PathBuf {
+            if matches.is_present("fullpath") {
+                grave.to_path_buf()
+            } else {
+                Path::new("/").join(shorten(graveyard, grave))
+            }
         };
 
-        let f = fs::File::open(record).chain_err(|| "Failed to read record")?;
         let stdout = io::stdout();
         let std_lock = stdout.lock();
-        let handle = io::BufWriter::new(std_lock);
+        let mut handle = io::BufWriter::new(std_lock);
+
+        if matches.is_present("plain") {
+            for grave in entries.iter().map(|entry| entry.dest.as_path()) {
+                if nocolor {
+                    // Written as raw bytes so scripts get back the exact file name
+                    handle.write_all(shown(grave).as_os_str().as_bytes())?;
+                    handle.write_all(b"\n")?;
+                } else {
+                    writeln!(handle, "{}", fmt_exp!(shown(grave), yellow))?;
+                }
+            }
+            handle.flush()?;
+            return Ok(());
+        }
+
         let mut tab_handle = tabwriter::TabWriter::new(handle);
 
-        for (i, grave) in seance(f, gravepath.to_string_lossy()).enumerate() {
-            let metadata = fs::metadata(&grave);
-            let created = match metadata.unwrap().clone().modified() {
-                Ok(v) => {
-                    let time: DateTime<Local> = v.into();
-                    format!("{}", time.format("%Y-%m-%d %T")).to_string()
-                },
-                _ => "N/A".to_string()
+        for (i, entry) in entries.iter().enumerate() {
+            let grave = &entry.dest;
+            let deleted = entry.deleted.with_timezone(&Local).format("%Y-%m-%d %T").to_string();
+            // Time the file was last modified before it was buried
+            let modified = if matches.is_present("modified") {
+                Some(match fs::symlink_metadata(grave).and_then(|m| m.modified()) {
+                    Ok(v) => {
+                        let time: DateTime<Local> = v.into();
+                        format!("{}", time.format("%Y-%m-%d %T"))
+                    },
+                    _ => "N/A".to_string()
+                })
+            } else {
+                None
             };
 
-            let otype = file_type(&grave);
+            let otype = file_type(grave);
+            let pin = if entry.pinned { " (pinned)" } else { "" };
 
             if nocolor {
-                if matches.is_present("fullpath") {
-                    if matches.is_present("plain") {
-                        println!("{}", grave.display().to_string());
-                    } else {
-                        writeln!(tab_handle, "{}\t{}\t{}\t{}",
-                            i.to_string(), created, otype, grave.display()
-                        )?;
-                    }
-                } else {
-                    let shortened = grave.display()
-                                         .to_string()
-                                         .replace(graveyard.to_str().unwrap(), "");
-
-                    if matches.is_present("plain") {
-                        println!("{}", shortened);
-                    } else {
-                        writeln!(tab_handle, "{}\t{}\t{}\t{}",
-                            i.to_string(), created, otype, shortened
-                        )?;
-                    }
+                write!(tab_handle, "{}\t{}\t", i, deleted)?;
+                if let Some(modified) = modified {
+                    write!(tab_handle, "{}\t", modified)?;
                 }
+                write!(tab_handle, "{}\t", otype)?;
+                tab_handle.write_all(shown(grave).as_os_str().as_bytes())?;
+                writeln!(tab_handle, "{}", pin)?;
             } else {
                 // let brbb = |s: &str| s.bright_blue().bold();
                 // let brcb = |s: &str| "=".repeat(s.len()).bright_cyan().bold();
                 // writeln!(tab_handle, "{}\t{}\t{}\t{}", brbb("Index"), brbb("Created"), brbb("Type"), brbb("File"))?;
                 // writeln!(tab_handle, "{}\t{}\t{}\t{}", brcb("Index"), brcb("Created"), brcb("Type"), brcb("File"))?;
 
-                if matches.is_present("fullpath") {
-                    if matches.is_present("plain") {
-                        println!("{}", fmt_exp!(grave, yellow));
-                    } else {
-                        writeln!(tab_handle, "{}\t{}\t{:<5}\t{}",
-                            i.to_string().green().bold(),
-                            created.magenta().bold(),
-                            otype.bright_red().bold(),
-                            fmt_exp!(grave, yellow)
-                        )?;
-                    }
-                } else {
-                    let shortened = grave.display()
-                                .to_string()
-                                .replace(graveyard.to_str().unwrap(), "")
-                                .yellow().bold();
-
-                    if matches.is_present("plain") {
-                        println!("{}", shortened);
-                    } else {
-                        writeln!(tab_handle, "{}\t{}\t{:<5}\t{}",
-                            i.to_string().green().bold(),
-                            created.magenta().bold(),
-                            otype.bright_red().bold(),
-                            shortened
-                        )?;
-                    }
+                write!(tab_handle, "{}\t{}\t",
+                    i.to_string().green().bold(),
+                    deleted.magenta().bold()
+                )?;
+                if let Some(modified) = modified {
+                    write!(tab_handle, "{}\t", modified.cyan().bold())?;
                 }
-                tab_handle.flush()?;
+                writeln!(tab_handle, "{:<5}\t{}{}",
+                    otype.bright_red().bold(),
+                    fmt_exp!(shown(grave), yellow),
+                    pin.blue().bold()
+                )?;
             }
         }
+        tab_handle.flush()?;
         return Ok(());
     }
 
-    if let Some(targets) = matches.values_of("TARGET") {
-        for target in targets {
+    if let Some(targets) = matches.values_of_os("TARGET") {
+        let mut buried = Vec::new();
+        for target in targets.map(Path::new) {
+            if interrupt::requested() {
+                break;
+            }
             // Check if source exists
             if let Ok(metadata) = fs::symlink_metadata(target) {
                 // Canonicalize the path unless it's a symlink
@@ -444,15 +733,8 @@ fn run() -> Result<()> {
                         // Get the size of the directory and all its contents
                         println!(
                             "{}: directory, {} including:",
-                            target.magenta().bold(),
-                            humanize_bytes(
-                                WalkDir::new(source)
-                                    .into_iter()
-                                    .filter_map(|x| x.ok())
-                                    .filter_map(|x| x.metadata().ok())
-                                    .map(|x| x.len())
-                                    .sum::<u64>()
-                            ).green().bold()
+                            fmt_exp!(target, magenta),
+                            humanize_bytes(total_size(source)).green().bold()
                         );
 
                         // Print the first few top-level files in the directory
@@ -467,7 +749,7 @@ fn run() -> Result<()> {
                         }
                     } else {
                         println!("{}: file, {}",
-                            target.magenta().bold(),
+                            fmt_exp!(target, magenta),
                             humanize_bytes(metadata.len()).green().bold()
                         );
                         // Read the file and print the first few lines
@@ -475,7 +757,7 @@ fn run() -> Result<()> {
                             for line in BufReader::new(f)
                                 .lines()
                                 .take(LINES_TO_INSPECT)
-                                .filter_map(|line| line.ok())
+                                .map_while(|line| line.ok())
                             {
                                 println!("> {}", line);
                             }
@@ -487,20 +769,18 @@ fn run() -> Result<()> {
                         }
                     }
                     if !prompt_yes(format!("Send {} to the graveyard?",
-                            target.magenta().bold())) {
+                            fmt_exp!(target, magenta))) {
                         continue;
                     }
                 }
 
                 // If rip is called on a file already in the graveyard, prompt
                 // to permanently delete it instead.
-                if source.starts_with(graveyard) {
+                if graveyard.contains(source) {
                     println!("{} is already in the graveyard.",
                         source.display().to_string().magenta().bold());
                     if prompt_yes("Permanently unlink it?") {
-                        if fs::remove_dir_all(source).is_err() {
-                            fs::remove_file(source).chain_err(|| "Couldn't unlink")?;
-                        }
+                        graveyard.purge(std::slice::from_ref(source))?;
                         continue;
                     } else {
                         println!("Skipping {}", fmt_exp!(source, magenta));
@@ -508,27 +788,82 @@ fn run() -> Result<()> {
                     }
                 }
 
-                let dest: &Path = &{
-                    let dest = join_absolute(graveyard, source);
-                    // Resolve a name conflict if necessary
-                    if symlink_exists(&dest) {
-                        rename_grave(dest)
-                    } else {
-                        dest
-                    }
-                };
-
-                bury(source, dest)
-                    .map_err(|e| {
-                        fs::remove_dir_all(dest).ok();
-                        e
-                    })
-                    .chain_err(|| "Failed to bury file")?;
-                // Clean up any partial buries due to permission error
-                write_log(source, dest, record)
-                    .chain_err(|| format!("Failed to write record at {}", record.display()))?;
+                match graveyard.bury(source) {
+                    Ok(burial) => {
+                        report_dropped(&burial.dropped, verbose);
+                        buried.push(burial.grave);
+                    },
+                    Err(rip::errors::Error(rip::errors::ErrorKind::NoDevice(_, devices), _)) => {
+                        // Device nodes can only be made when running privileged
+                        for device in &devices {
+                            println!("Non-regular file or directory: {}", fmt_exp!(device, magenta));
+                        }
+                        if !prompt_yes("Permanently delete the file?") {
+                            bail!("Left {} where it was, since it can't be copied to the graveyard",
+                                target.display());
+                        }
+                        let burial = graveyard.clone().markers(true).bury(source)?;
+                        report_dropped(&burial.dropped, verbose);
+                        buried.push(burial.grave);
+                    },
+                    Err(rip::errors::Error(rip::errors::ErrorKind::NoRoom(_, shortfall), _)) => {
+                        println!("{} needs {}, but only {} is left {}",
+                            fmt_exp!(target, magenta),
+                            humanize_bytes(shortfall.needed).green().bold(),
+                            humanize_bytes(shortfall.available).red().bold(),
+                            match shortfall.limit {
+                                Limit::MaxSize => "under the size limit of the graveyard",
+                                Limit::MinFree => "on the filesystem of the graveyard",
+                            }
+                        );
+                        // Expiring graves is only offered if it can make enough room
+                        let choice = if shortfall.reclaimable() {
+                            prompt_choice(format!(
+                                "Expire the oldest graves to free {}, permanently delete it instead, or abort?",
+                                humanize_bytes(shortfall.deficit())
+                            ), "gdA")
+                        } else {
+                            prompt_choice("Permanently delete it instead, or abort?", "dA")
+                        };
+                        match choice {
+                            'g' => {
+                                for (entry, size) in graveyard.make_room(&shortfall, &buried)? {
+                                    println!("Expired {} ({})",
+                                        fmt_exp!(entry.orig, magenta),
+                                        humanize_bytes(size).green().bold()
+                                    );
+                                }
+                                let burial = graveyard.bury(source)?;
+                                report_dropped(&burial.dropped, verbose);
+                                buried.push(burial.grave);
+                            },
+                            'd' => {
+                                if metadata.is_dir() {
+                                    fs::remove_dir_all(source)
+                                } else {
+                                    fs::remove_file(source)
+                                }.chain_err(|| format!("Couldn't unlink {}", source.display()))?;
+                                println!("Permanently deleted {}", fmt_exp!(target, magenta));
+                            },
+                            _ => bail!("Aborted, so {} was left where it was", target.display()),
+                        }
+                    },
+                    Err(e) => return Err(e.into()),
+                }
             } else {
-                bail!("Cannot remove {}: no such file or directory", target);
+                bail!("Cannot remove {}: no such file or directory", target.display());
+            }
+        }
+
+        // Make room by expiring old graves, but never the ones just buried
+        if config.gc.auto && !interrupt::requested() {
+            let retention = rip::Retention { spare: buried, ..config.gc.retention()? };
+            if !retention.is_empty() {
+                for (entry, size) in graveyard.gc(&retention)? {
+                    if verbose {
+                        verbose!("expired", format!("{} ({})", entry.orig.display(), humanize_bytes(size)));
+                    }
+                }
             }
         }
     }
@@ -560,6 +895,9 @@ fn run() -> Result<()> {
                 for (needle, replacement) in comp_helper::ZSH_COMPLETION_REP {
                     replace(&mut script, needle, replacement)?;
                 }
+                for (start, end, replacement) in comp_helper::ZSH_COMPLETION_SPANS {
+                    replace_span(&mut script, start, end, replacement)?;
+                }
             },
             _ => println!(),
         }
@@ -598,10 +936,17 @@ instead of unlinking them.",
         )
         .arg(
             Arg::new("decompose")
-                .about("Permanently deletes (unlink) the entire graveyard")
+                .about("Permanently deletes (unlink) the entire graveyard, or part of it with -l or -o")
                 .short('d')
                 .long("decompose"),
         )
+        .arg(
+            Arg::new("force")
+                .about("Also unlinks pinned graves (with -d)")
+                .short('F')
+                .long("force")
+                .requires("decompose"),
+        )
         .arg(
             Arg::new("seance")
                 .about("Prints files that were sent under the current directory")
@@ -616,12 +961,23 @@ instead of unlinking them.",
                 // It'd be nice to have a requires for two other args using an or
                 // seance, decompose
         )
+        .arg(
+            Arg::new("modified")
+                .about("Also prints when each file was last modified (with -s)")
+                .long_about(
+                    "Adds a column with the time each file was last modified, next to the \
+                    time it was sent to the graveyard"
+                )
+                .short('M')
+                .long("modified")
+                .requires("seance"),
+        )
         .arg(
             Arg::new("all")
-                .about("Prints all files in graveyard")
+                .about("Prints all files in graveyard (with -s), or counts indices across it")
                 .short('a')
                 .long("all")
-                .requires("seance"),
+                .requires("listed"),
         )
         // TODO: Use this everywhere
         .arg(
@@ -641,20 +997,123 @@ instead of unlinking them.",
                     syntax, or combine with '-s' to undo all files that have been \
                     removed in current directory. Globbing syntax involves: \
                     *glob, **glob, *.{png,jpg,gif}, and using '!' before all previous \
-                    mentioned globs to negate them. If '-l' is passed with no arguments, the most \
-                    recently deleted file from '$CWD' will be returned."
+                    mentioned globs to negate them. Files can also be picked by the indices printed \
+                    by '-s', as '@3', '@0-4' or '@1,5,7', counted across the graveyard with '-a'. \
+                    If '-l' is passed with no arguments, the most recently deleted file from '$CWD' \
+                    will be returned."
                 )
                 .short('u')
                 .long("unbury")
                 .value_name("target")
                 .min_values(0),
         )
+        .arg(
+            Arg::new("purge")
+                .about(
+                    "Permanently unlink some file(s) in the graveyard, picked the same \
+                    way as with -u. Can be glob, or combined with -s (see --help)"
+                )
+                .long_about(
+                    "Permanently unlink files in the graveyard and remove them from the record. \
+                    Files are picked the same way as with '-u': by path or glob, '-l' to look \
+                    under '$CWD', '-s' for everything printed by '-s', '@n' or '--index' for the \
+                    indices printed by '-s', and '--older-than' for files buried long enough ago. Use \
+                    '--dry-run' to print what would be unlinked."
+                )
+                .short('P')
+                .long("purge")
+                .value_name("target")
+                .min_values(0),
+        )
+        .arg(
+            Arg::new("tree")
+                .about(
+                    "Prints what is inside of some file(s) in the graveyard without restoring \
+                    them, picked the same way as with -u"
+                )
+                .long_about(
+                    "Prints the tree of each file picked the same way as with '-u', with the type \
+                    and size of everything inside of it, without restoring anything. With no \
+                    arguments, the most recently deleted file is printed, or the one from '$CWD' \
+                    with '-l'."
+                )
+                .short('T')
+                .long("tree")
+                .value_name("target")
+                .min_values(0),
+        )
+        .group(ArgGroup::new("exhume").args(&["unbury", "purge", "tree"]))
+        .arg(
+            Arg::new("to")
+                .about("Restores files under this directory instead of where they were (with -u)")
+                .long_about(
+                    "Restores the files picked by '-u' under this directory instead of where they \
+                    were buried from, leaving the original locations alone. Each file keeps its name, \
+                    or its original path with '--parents': the part below the current directory, \
+                    or all of it for files buried from elsewhere."
+                )
+                .short('t')
+                .long("to")
+                .value_name("dir")
+                .takes_value(true)
+                .requires("unbury"),
+        )
+        .arg(
+            Arg::new("conflict")
+                .about("What to do when a file is returned to a path that is taken (with -u)")
+                .long_about(
+                    "What to do when a file is returned to a path that is taken: 'rename' it to \
+                    a numbered name (the default), 'overwrite' what is there after burying it, \
+                    'skip' it, 'prompt' for one of those, or 'fail'. Defaults to 'conflict' in \
+                    the [unbury] section of the config."
+                )
+                .long("conflict")
+                .value_name("strategy")
+                .takes_value(true)
+                .possible_values(&["rename", "overwrite", "skip", "prompt", "fail"])
+                .requires("unbury"),
+        )
+        .arg(
+            Arg::new("parents")
+                .about("Keeps the original path of each file under the directory given to --to")
+                .long("parents")
+                .requires("to"),
+        )
+        .group(ArgGroup::new("scope").args(&["unbury", "purge", "tree", "decompose"]).multiple(true))
+        .arg(
+            Arg::new("index")
+                .about("Picks files by the index printed by -s, like @n (with -u, -P or -T)")
+                .short('I')
+                .long("index")
+                .value_name("n")
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .use_delimiter(true)
+                .requires("exhume"),
+        )
+        .group(ArgGroup::new("listed").args(&["seance", "index", "unbury", "purge", "tree"]).multiple(true))
+        .arg(
+            Arg::new("older-than")
+                .about("Picks files buried longer ago than this, e.g., 30d (with -u, -P, -T or -d)")
+                .short('o')
+                .long("older-than")
+                .value_name("age")
+                .takes_value(true)
+                .requires("scope"),
+        )
+        .arg(
+            Arg::new("dry-run")
+                .about("Prints what would be purged without unlinking anything (with -P)")
+                .short('n')
+                .long("dry-run")
+                .requires("purge"),
+        )
         .arg(
             Arg::new("max-depth")
                 .about("Set max depth for glob to search (default: 10)")
                 .short('m')
                 .long("max-depth")
-                .requires("unbury")
+                .requires("exhume")
                 .takes_value(true),
         )
         // TODO: use with glob
@@ -666,11 +1125,12 @@ instead of unlinking them.",
                     below the directory that you are in, you have to specify that directory. For example \
                     if you're in a directory with a subdirectory 'src', and a file is in the $GRAVEYARD \
                     as $GRAVEYARD/$PWD/src/<file>, you must type 'src/<file>' for it to be unburied. \
-                    If a file is not specified, it will return the most recently deleted file from the local directory."
+                    If a file is not specified, it will return the most recently deleted file from the local directory. \
+                    With '-d', only what was buried from under the current directory is unlinked."
                 )
                 .short('l')
                 .long("local")
-                .requires("unbury"),
+                .requires("scope"),
         )
         .arg(
             Arg::new("plain")
@@ -688,12 +1148,121 @@ instead of unlinking them.",
                 .short('i')
                 .long("inspect"),
         )
+        .arg(
+            Arg::new("checksum")
+                .about("Checksums files copied across filesystems before unlinking them")
+                .long_about(
+                    "When a file has to be copied to the graveyard, checks that the contents and \
+                    mode of the copy match the original before unlinking it. The checksum of \
+                    the grave is kept in the record, so that 'rip verify' can tell if it changes."
+                )
+                .short('c')
+                .long("checksum"),
+        )
         .arg(
             Arg::new("verbose")
                 .about("Print what is going on")
                 .short('v')
                 .long("verbose"),
         )
+        .subcommand(
+            App::new("verify")
+                .about("Checks graves against the checksums taken when they were buried")
+                .long_about(
+                    "Checks graves against the checksums taken when they were buried with \
+                    '--checksum', to find graves that were tampered with or have rotted. \
+                    Checks every grave that has a checksum if none are given."
+                )
+                .arg(
+                    Arg::new("GRAVE")
+                        .about("Grave to check, inside or relative to the graveyard, or @n for the index printed by -s")
+                        .takes_value(true)
+                        .multiple(true),
+                )
+                .arg(
+                    Arg::new("all")
+                        .about("Counts @n across the whole graveyard, like -sa")
+                        .short('a')
+                        .long("all"),
+                ),
+        )
+        .subcommand(
+            App::new("doctor")
+                .about("Finds where the record and the graveyard disagree")
+                .long_about(
+                    "Finds graves that aren't in the record (orphans), lines in the record for \
+                    graves that are gone, are recorded twice, or are outside of the graveyard, and \
+                    lines that can't be read. With '--repair', orphans are added to the record \
+                    using the path they were probably buried from, and the other lines are removed."
+                )
+                .arg(
+                    Arg::new("repair")
+                        .about("Repairs the problems that are found")
+                        .short('r')
+                        .long("repair"),
+                ),
+        )
+        .subcommand(
+            App::new("gc")
+                .about("Permanently deletes graves that are too old, or don't fit in the graveyard")
+                .long_about(
+                    "Permanently deletes the graves that were buried longer ago than '--older-than', \
+                    and then the oldest graves until the graveyard takes no more than '--max-size' \
+                    on disk. Both default to the values in the [gc] section of the config, where \
+                    'auto = true' collects garbage after each burial."
+                )
+                .arg(
+                    Arg::new("older-than")
+                        .about("Expires graves buried longer ago than this (e.g., 30d, 12h, 2w)")
+                        .short('o')
+                        .long("older-than")
+                        .value_name("age")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("max-size")
+                        .about("Expires the oldest graves until the rest fit in this (e.g., 500M, 10G)")
+                        .short('S')
+                        .long("max-size")
+                        .value_name("size")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("force")
+                        .about("Expires pinned graves too")
+                        .short('f')
+                        .long("force"),
+                )
+                .arg(
+                    Arg::new("dry-run")
+                        .about("Prints what would be expired without deleting anything")
+                        .short('n')
+                        .long("dry-run"),
+                ),
+        )
+        .subcommand(
+            App::new("pin")
+                .about("Pins graves, so that they aren't expired or decomposed unless forced")
+                .arg(
+                    Arg::new("GRAVE")
+                        .about("Grave to pin, inside or relative to the graveyard, or @n for the index printed by -s")
+                        .takes_value(true)
+                        .multiple(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("all")
+                        .about("Counts @n across the whole graveyard, like -sa")
+                        .short('a')
+                        .long("all"),
+                )
+                .arg(
+                    Arg::new("remove")
+                        .about("Unpins the graves instead")
+                        .short('r')
+                        .long("remove"),
+                ),
+        )
         .subcommand(
             App::new("completions")
                 .version(crate_version!())
@@ -717,268 +1286,362 @@ pub fn print_completions<G: Generator>(app: &mut App, cursor: &mut Cursor<Vec<u8
     generate::<G, _>(app, app.get_name().to_string(), cursor);
 }
 
-/// Get the file's file type for displaying it
-fn file_type(p: &Path) -> String {
-    if fs::metadata(p).unwrap().is_file() { String::from("file") }
-    else if fs::metadata(p).unwrap().is_dir() { String::from("dir") }
-    else { String::from("other") }
-}
+/// Graves picked by `targets` (paths in the graveyard, a glob, or indices like
+/// `@3`), along with -s, --index and --older-than. This is shared by -u, -P
+/// and -T.
+fn select_graves(
+    matches: &ArgMatches,
+    targets: &[&OsStr],
+    graveyard: &Graveyard,
+    cwd: &Path,
+    verbose: bool,
+) -> Result<Vec<PathBuf>> {
+    let all = matches.is_present("all");
+    // Where a target given by path is in the graveyard
+    let grave_of = |file: &OsStr| -> PathBuf {
+        if matches.is_present("local") {
+            // Match files in local directory
+            join_absolute(join_absolute(graveyard.path(), cwd), file)
+        } else if graveyard.contains(file) {
+            // Full path given (including graveyard)
+            PathBuf::from(file)
+        } else {
+            // Full path given (excluding graveyard, i.e., starting from $HOME)
+            join_absolute(graveyard.path(), file)
+        }
+    };
+    // A grave that is really named like `@2x.png` is taken by its name
+    let (selectors, targets): (Vec<&OsStr>, Vec<&OsStr>) = targets.iter().partition(|target| {
+        parse_selector(target).is_some() && !symlink_exists(grave_of(target))
+    });
+
+    // Maybe a cleaner way? This is to detect if a glob is given (*glob, **glob)
+    let glob = targets.first()
+        .is_some_and(|s| s.as_bytes().contains(&b'*'));
+
+    if verbose { verbose!("globbing", glob); }
+
+    // Vector to hold the grave path of items we want to unbury.
+    // This will be used to determine which items to remove from the
+    // record following the unbury.
+    // Initialize it with the targets passed to -u or -P
+    let mut graves_to_exhume = {
+        if glob {
+            let max_d = if let Some(max_depth) = matches.value_of("max-depth") {
+                max_depth.parse::<usize>()
+                    .map_err(|_| format!("Invalid max depth: {}", max_depth))?
+            } else {
+                DEFAULT_MAX_DEPTH
+            };
+            if verbose { verbose!("max depth", max_d); }
+
+            let pattern = targets[0].to_string_lossy();
+            let graves = if matches.is_present("local") {
+                glob_walk(
+                    &pattern,
+                    join_absolute(graveyard.path(), cwd),
+                    max_d
+                )?
+            } else {
+                glob_walk(
+                    &pattern,
+                    graveyard.path(),
+                    max_d
+                )?
+            };
+            if graves.is_empty() {
+                bail!("No graves match {}", pattern.red().bold());
+            }
+            graves
+        } else {
+            let mut graves = Vec::new();
+            for file in &targets {
+                let grave = grave_of(file);
+                if !symlink_exists(&grave) {
+                    bail!("{} not found in graveyard", fmt_exp!(Path::new(file), red));
+                }
+                graves.push(grave);
+            }
+            graves
+        }
+    };
 
-/// Replace parts of completions output
-fn replace(
-    haystack: &mut String,
-    needle: &str,
-    replacement: &str
-) -> Result<()> {
-    if let Some(index) = haystack.find(needle) {
-        haystack.replace_range(index..index + needle.len(), replacement);
-        Ok(())
-    } else {
-        Err(ErrorKind::MismatchedCompletion(
-                needle.to_string().red().bold(),
-                haystack.to_string().green().bold()
-            ).into()
-        )
+    // Indices are the ones printed by -s, or by -sa with --all. --index is
+    // the same as giving them with `@`.
+    let indices = selectors
+        .into_iter()
+        .filter_map(parse_selector)
+        .chain(matches.values_of("index").into_iter().flatten().map(|i| i.trim_start_matches('@')));
+    for indices in indices {
+        graves_to_exhume.extend(graves_at(graveyard, cwd, all, indices)?);
     }
-}
-
-/// Write deletion history to record
-fn write_log<S, D, R>(source: S, dest: D, record: R) -> io::Result<()>
-where
-    S: AsRef<Path>,
-    D: AsRef<Path>,
-    R: AsRef<Path>,
-{
-    let (source, dest) = (source.as_ref(), dest.as_ref());
-    let mut f = fs::OpenOptions::new()
-        .create(true)
-        .append(true)
-        .open(record)?;
-    writeln!(
-        f,
-        "{}\t{}\t{}",
-        time::now().ctime(),
-        source.display(),
-        dest.display()
-    )?;
 
-    Ok(())
-}
+    if verbose { verbosed!("exhumed cli matches", graves_to_exhume); }
+    // If -s is also passed, push all files found by seance onto
+    // the graves_to_exhume.
+    if matches.is_present("seance") {
+        if let Ok(graves) = graveyard.list(Some(cwd)) {
+            graves_to_exhume.extend(graves.into_iter().map(|entry| entry.dest));
+        }
+        if verbose { verbosed!("exhumed after seance", graves_to_exhume); }
+    }
 
-fn bury<S: AsRef<Path>, D: AsRef<Path>>(source: S, dest: D) -> Result<()> {
-    let (source, dest) = (source.as_ref(), dest.as_ref());
-    // Try a simple rename, which will only work within the same mount point.
-    // Trying to rename across filesystems will throw errno 18.
-    if fs::rename(source, dest).is_ok() {
-        return Ok(());
+    // Narrow down what was picked to the old graves, or pick every old grave
+    // if nothing else was
+    if let Some(age) = matches.value_of("older-than") {
+        let cutoff = parse_cutoff(age)?;
+        let listed = if matches.is_present("local") {
+            graveyard.list(Some(cwd))?
+        } else {
+            graveyard.list(None)?
+        };
+        let old = listed.into_iter()
+            .filter(|entry| entry.deleted.timestamp() < cutoff)
+            .map(|entry| entry.dest);
+        if targets.is_empty() && !matches.is_present("seance") && !matches.is_present("index") {
+            graves_to_exhume.extend(old);
+        } else {
+            let old = old.collect::<Vec<_>>();
+            graves_to_exhume.retain(|grave| old.contains(grave));
+        }
+        if verbose { verbosed!("exhumed after age", graves_to_exhume); }
     }
 
-    // If that didn't work, then copy and rm.
-    let parent = dest.parent().ok_or("Couldn't get parent of dest")?;
-    fs::create_dir_all(parent).chain_err(|| "Couldn't create parent dir")?;
+    Ok(dedup(graves_to_exhume))
+}
 
-    if fs::symlink_metadata(source)
-        .chain_err(|| "Couldn't get metadata")?
-        .is_dir()
-    {
-        // for x in globwalk::glob() {
-        // }
-        // Walk the source, creating directories and copying files as needed
-        for entry in WalkDir::new(source).into_iter().filter_map(|e| e.ok()) {
-            // Path without the top-level directory
-            let orphan: &Path = entry
-                .path()
-                .strip_prefix(source)
-                .chain_err(|| "Parent directory isn't a prefix of child directories?")?;
-            if entry.file_type().is_dir() {
-                fs::create_dir_all(dest.join(orphan)).chain_err(|| {
-                    format!(
-                        "Failed to create {} in {}",
-                        entry.path().display(),
-                        dest.join(orphan).display()
-                    )
-                })?;
-            } else {
-                copy_file(entry.path(), dest.join(orphan)).chain_err(|| {
-                    format!(
-                        "Failed to copy file from {} to {}",
-                        entry.path().display(),
-                        dest.join(orphan).display()
-                    )
-                })?;
-            }
-        }
-        fs::remove_dir_all(source)
-            .chain_err(|| format!("Failed to remove dir: {}", source.display()))?;
-    } else {
-        copy_file(source, dest).chain_err(|| {
+/// Print the extended attributes that couldn't be carried over to a copy, with -v
+fn report_dropped(dropped: &[Dropped], verbose: bool) {
+    if !verbose {
+        return;
+    }
+    for attr in dropped {
+        verbose!(
+            "xattr",
             format!(
-                "Failed to copy file from {} to {}",
-                source.display(),
-                dest.display()
+                "couldn't carry over {} to {}: {}",
+                attr.name.to_string_lossy(),
+                attr.path.display(),
+                attr.reason
             )
-        })?;
-        fs::remove_file(source)
-            .chain_err(|| format!("Failed to remove file: {}", source.display()))?;
+        );
     }
-
-    Ok(())
 }
 
-fn copy_file<S: AsRef<Path>, D: AsRef<Path>>(source: S, dest: D) -> io::Result<()> {
-    let (source, dest) = (source.as_ref(), dest.as_ref());
-    let metadata = fs::symlink_metadata(source)?;
-    let filetype = metadata.file_type();
+/// The deepest directory that all of `dirs` are in
+fn common_root<'a, I: Iterator<Item = &'a Path>>(mut dirs: I) -> PathBuf {
+    let mut root = dirs.next().map(Path::to_path_buf).unwrap_or_default();
+    for dir in dirs {
+        while !dir.starts_with(&root) && root.pop() {}
+    }
+    root
+}
 
-    if metadata.len() > BIG_FILE_THRESHOLD {
-        println!(
-            "About to copy a big file ({} is {})",
-            source.display(),
-            humanize_bytes(metadata.len())
-        );
-        if prompt_yes("Permanently delete this file instead?") {
-            return Ok(());
+/// Everything at the top of the graveyard other than its own files, along with
+/// where it was buried from, for when the record can't be read
+fn unrecorded(graveyard: &Graveyard) -> Result<Vec<(PathBuf, PathBuf)>> {
+    let mut graves = Vec::new();
+    for entry in fs::read_dir(graveyard.path()).chain_err(|| "Couldn't read the graveyard")? {
+        let grave = entry.chain_err(|| "Couldn't read the graveyard")?.path();
+        let name = grave.file_name().unwrap_or_default();
+        if [RECORD, JOURNAL, LOCK].iter().any(|internal| name == OsStr::new(internal))
+            || name == OsStr::new(&format!("{}.tmp", RECORD))
+        {
+            continue;
         }
+        graves.push((Path::new("/").join(name), grave));
     }
+    graves.sort();
+    Ok(graves)
+}
 
-    if filetype.is_file() {
-        if let Err(e) = fs::copy(source, dest) {
-            // println!("Failed to copy {} to {}", source.display(), dest.display());
-            return Err(e);
-        }
-    } else if filetype.is_fifo() {
-        let mode = metadata.permissions().mode();
-        std::process::Command::new("mkfifo")
-            .arg(dest)
-            .arg("-m")
-            .arg(mode.to_string());
-    } else if filetype.is_symlink() {
-        let target = fs::read_link(source)?;
-        std::os::unix::fs::symlink(target, dest)?;
-    } else if let Err(e) = fs::copy(source, dest) {
-        // Special file: Try copying it as normal, but this probably won't work
-        println!("Non-regular file or directory: {}", source.display());
-        if !prompt_yes("Permanently delete the file?") {
-            return Err(e);
-        }
-        // Create a dummy file to act as a marker in the graveyard
-        let mut marker = fs::File::create(dest)?;
-        marker.write_all(
-            b"This is a marker for a file that was \
-                           permanently deleted.  Requiescat in pace.",
-        )?;
+/// The last grave buried, from under the current directory with -l, or from
+/// anywhere otherwise
+fn last_grave(matches: &ArgMatches, graveyard: &Graveyard, cwd: &Path, verbose: bool) -> Option<PathBuf> {
+    let grave = if matches.is_present("local") {
+        if verbose { verbose!("exhuming", "locally"); }
+        graveyard.last_bury(Some(cwd)).ok()
+    } else {
+        if verbose { verbose!("exhuming", "globally"); }
+        graveyard.last_bury(None).ok()
+    };
+    if verbose { verbosed!("exhumed last bury", grave); }
+    grave
+}
+
+/// Indices given as `@3`, `@0-4` or `@1,5,7`, without the `@`, or `None` if
+/// `arg` doesn't look like one, i.e., doesn't start with `@` and a digit
+fn parse_selector(arg: &OsStr) -> Option<&str> {
+    let indices = arg.to_str()?.strip_prefix('@')?;
+    indices.starts_with(|c: char| c.is_ascii_digit()).then_some(indices)
+}
+
+/// Indices given as `3`, `0-4` or `1,5,7`, or a mix of them, into a listing of
+/// `len` graves. Each index is only returned once.
+fn parse_indices(s: &str, len: usize) -> Result<Vec<usize>> {
+    let invalid = || Error::from(format!("Invalid index: @{}", s));
+    let mut indices = Vec::new();
+    for part in s.split(',') {
+        let (start, end) = match part.split_once('-') {
+            Some((start, end)) => (
+                start.parse::<usize>().map_err(|_| invalid())?,
+                end.parse::<usize>().map_err(|_| invalid())?,
+            ),
+            None => {
+                let index = part.parse::<usize>().map_err(|_| invalid())?;
+                (index, index)
+            },
+        };
+        if start > end {
+            return Err(invalid());
+        }
+        // Checked before expanding, so that a range is never longer than the listing
+        if end >= len {
+            bail!("No grave at index {}", end);
+        }
+        indices.extend(start..=end);
     }
+    let mut seen = HashSet::new();
+    indices.retain(|&i| seen.insert(i));
+    Ok(indices)
+}
 
-    Ok(())
+/// Graves at `indices` (as given to [`parse_indices`]) in what -s prints, or
+/// -sa if `all`
+fn graves_at(graveyard: &Graveyard, cwd: &Path, all: bool, indices: &str) -> Result<Vec<PathBuf>> {
+    let listed = if all {
+        graveyard.list(None)?
+    } else {
+        graveyard.list(Some(cwd))?
+    };
+    Ok(parse_indices(indices, listed.len())?
+        .into_iter()
+        .map(|i| listed[i].dest.clone())
+        .collect())
 }
 
-/// Return the path in the graveyard of the last file to be buried.
-/// As a side effect, any valid last files that are found in the record but
-/// not on the filesystem are removed from the record.
-fn get_last_bury<R>(record: R, cwd: &Path, cwdp: &str) -> io::Result<PathBuf>
+/// Graves given to a subcommand, by path or by indices like `@3`
+fn resolve_graves<'a, I>(graveyard: &Graveyard, cwd: &Path, all: bool, graves: I) -> Result<Vec<PathBuf>>
 where
-    R: AsRef<Path>,
+    I: Iterator<Item = &'a OsStr>,
 {
-    let graves_to_exhume: &mut Vec<PathBuf> = &mut Vec::new();
-    let mut f = fs::File::open(record.as_ref())?;
-    let mut contents = String::new();
-    f.read_to_string(&mut contents)?;
-
-    for entry in contents.lines().rev().map(record_entry) {
-        if cwdp == "local" {
-            // If local and doesn't contain path to cwd, continue
-            // Trying to exhume file that's not last bury globally, but locally
-            if !entry.dest.to_str().unwrap()
-                .contains(cwd.to_str().unwrap()) {
-                    continue;
-            } else if symlink_exists(entry.dest) {
-                if !graves_to_exhume.is_empty() {
-                    delete_lines_from_record(f, record, graves_to_exhume)?;
-                }
-                return Ok(PathBuf::from(entry.dest));
-            } else {
-                // File is gone, mark the grave to be removed from the record
-                graves_to_exhume.push(PathBuf::from(entry.dest));
-            }
-        } else if cwdp == "global" {
-            // Check that the file is still in the graveyard.
-            // If it is, return the corresponding line.
-            if symlink_exists(entry.dest) {
-                if !graves_to_exhume.is_empty() {
-                    delete_lines_from_record(f, record, graves_to_exhume)?;
-                }
-                return Ok(PathBuf::from(entry.dest));
-            } else {
-                // File is gone, mark the grave to be removed from the record
-                graves_to_exhume.push(PathBuf::from(entry.dest));
-            }
+    let mut resolved = Vec::new();
+    for grave in graves {
+        let path = resolve_grave(graveyard, grave);
+        match parse_selector(grave) {
+            Some(indices) if !symlink_exists(&path) => {
+                resolved.extend(graves_at(graveyard, cwd, all, indices)?)
+            },
+            _ => resolved.push(path),
         }
     }
+    Ok(dedup(resolved))
+}
 
-    if !graves_to_exhume.is_empty() {
-        delete_lines_from_record(f, record, graves_to_exhume)?;
-    }
-    Err(io::Error::new(io::ErrorKind::NotFound, "But nobody came"))
+/// `graves` without the ones given more than once
+fn dedup(mut graves: Vec<PathBuf>) -> Vec<PathBuf> {
+    let mut seen = HashSet::new();
+    graves.retain(|grave| seen.insert(grave.clone()));
+    graves
+}
+
+/// Unix timestamp of the time that `age`, as given on the command line, was ago
+fn parse_cutoff(age: &str) -> Result<i64> {
+    parse_duration(age)
+        .and_then(cutoff)
+        .ok_or_else(|| format!("Invalid age: {}", age).into())
 }
 
-/// Parse a line in the record into a `RecordItem`
-fn record_entry(line: &str) -> RecordItem {
-    let mut tokens = line.split('\t');
-    let time: &str = tokens.next().expect("Bad format: column A");
-    let orig: &str = tokens.next().expect("Bad format: column B");
-    let dest: &str = tokens.next().expect("Bad format: column C");
-    RecordItem {
-        _time: time,
-        orig: Path::new(orig),
-        dest: Path::new(dest),
+/// Path of a grave given on the command line, either inside the graveyard or
+/// relative to it
+fn resolve_grave(graveyard: &Graveyard, grave: &OsStr) -> PathBuf {
+    if graveyard.contains(grave) {
+        PathBuf::from(grave)
+    } else {
+        join_absolute(graveyard.path(), grave)
     }
 }
 
-/// Takes a vector of grave paths and returns the respective lines in the record
-#[allow(clippy::needless_lifetimes)]
-fn lines_of_graves<'a>(f: fs::File, graves: &'a [PathBuf]) -> impl Iterator<Item = String> + 'a {
-    BufReader::new(f)
-        .lines()
-        .filter_map(|l| l.ok())
-        .filter(move |l| graves.iter().any(|y| y == record_entry(l).dest))
+/// Size of `path` and everything under it, as printed by -i and -T
+fn total_size(path: &Path) -> u64 {
+    walk(path)
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.metadata.len())
+        .sum()
+}
+
+/// Everything under `grave`, in the order it is printed by -T, along with the
+/// size of each including what is inside of it. The sizes are added up from
+/// the bottom, so that the tree is only walked once.
+fn tree(grave: &Path) -> Vec<(Entry, u64)> {
+    let entries = walk(grave).filter_map(|entry| entry.ok()).collect::<Vec<_>>();
+
+    // Everything inside of an entry comes right after it, so going backwards,
+    // the sizes of its children are added up by the time it is reached
+    let mut sizes = vec![0; entries.len()];
+    let mut children: Vec<u64> = Vec::new();
+    for (i, entry) in entries.iter().enumerate().rev() {
+        let depth = entry.depth;
+        if children.len() < depth + 2 {
+            children.resize(depth + 2, 0);
+        }
+        sizes[i] = entry.metadata.len() + std::mem::take(&mut children[depth + 1]);
+        children[depth] += sizes[i];
+    }
+
+    entries.into_iter().zip(sizes).collect()
 }
 
-/// Returns an iterator over all graves in the record that are under gravepath
-fn seance<T: AsRef<str>>(f: fs::File, gravepath: T) -> impl Iterator<Item = PathBuf> {
-    BufReader::new(f)
-        .lines()
-        .filter_map(|l| l.ok())
-        .map(|l| PathBuf::from(record_entry(&l).dest))
-        .filter(move |d| d.starts_with(gravepath.as_ref()))
+/// Path of `grave` relative to the graveyard
+fn shorten<'a>(graveyard: &Graveyard, grave: &'a Path) -> &'a Path {
+    grave.strip_prefix(graveyard.path()).unwrap_or(grave)
 }
 
-/// Takes a vector of grave paths and removes the respective lines from the record
-fn delete_lines_from_record<R: AsRef<Path>>(
-    f: fs::File,
-    record: R,
-    graves: &[PathBuf],
-) -> io::Result<()> {
-    let record = record.as_ref();
-    // Get the lines to write back to the record, which is every line except
-    // the ones matching the exhumed graves.  Store them in a vector
-    // since we'll be overwriting the record in-place.
-    let lines_to_write: Vec<String> = BufReader::new(f)
-        .lines()
-        .filter_map(|l| l.ok())
-        .filter(|l| !graves.iter().any(|y| y == record_entry(l).dest))
-        .collect();
-    let mut f = fs::File::create(record)?;
-    for line in lines_to_write {
-        writeln!(f, "{}", line)?;
+/// Replace parts of completions output
+fn replace(
+    haystack: &mut String,
+    needle: &str,
+    replacement: &str
+) -> Result<()> {
+    if let Some(index) = haystack.find(needle) {
+        haystack.replace_range(index..index + needle.len(), replacement);
+        Ok(())
+    } else {
+        Err(ErrorKind::MismatchedCompletion(
+                needle.to_string().red().bold(),
+                haystack.to_string().green().bold()
+            ).into()
+        )
     }
+}
 
-    Ok(())
+/// Replace the part of completions output from `start` through `end`
+fn replace_span(
+    haystack: &mut String,
+    start: &str,
+    end: &str,
+    replacement: &str
+) -> Result<()> {
+    let span = haystack.find(start).and_then(|i|
+        haystack[i..].find(end).map(|j| i..i + j + end.len())
+    );
+    if let Some(span) = span {
+        haystack.replace_range(span, replacement);
+        Ok(())
+    } else {
+        Err(ErrorKind::MismatchedCompletion(
+                format!("{}…{}", start, end).red().bold(),
+                haystack.to_string().green().bold()
+            ).into()
+        )
+    }
 }
 
 /// Create a GlobWalkerBuilder object that traverses the base directory, picking up
 /// each file matching the pattern.
-fn glob_walker<S>(base: S, pattern: S, max_depth: usize) -> Result<GlobWalker>
+fn glob_walker<P, S>(base: P, pattern: S, max_depth: usize) -> Result<GlobWalker>
 where
+    P: AsRef<Path>,
     S: AsRef<str>,
 {
     let builder = GlobWalkerBuilder::new(
@@ -990,7 +1653,7 @@ where
         .max_depth(max_depth)
         .build()
         .map_err(|e|
-            Error::with_chain(e, "Invalid data")
+            Error::with_chain(e, format!("Invalid glob: {}", pattern.as_ref()))
         )
 }
 
@@ -1000,23 +1663,21 @@ fn glob_walk<P>(
     pattern: &str,
     base_path: P,
     max_depth: usize,
-) -> Vec<PathBuf>
+) -> Result<Vec<PathBuf>>
 where
     P: AsRef<Path>,
 {
     let mut globbed_paths: Vec<PathBuf> = Vec::new();
-    let base_path = base_path.as_ref().to_string_lossy().to_string();
 
     for entry in glob_walker(
-        base_path.as_str(),
+        base_path,
         pattern,
         max_depth
-        )
-        .unwrap()
+        )?
         .flatten()
     {
         globbed_paths.push(PathBuf::from(entry.path()));
     }
 
-    globbed_paths
+    Ok(globbed_paths)
 }
@@ -0,0 +1,166 @@
+//! Limits on the space the graveyard takes, which are checked before anything
+//! is buried, so that a copy to a graveyard on a small filesystem doesn't fill
+//! it up halfway through.
+use std::{
+    ffi::CString,
+    fs, io,
+    mem::MaybeUninit,
+    os::unix::{ffi::OsStrExt, fs::MetadataExt},
+    path::{Path, PathBuf},
+};
+
+use crate::errors::*;
+use crate::gc::Retention;
+use crate::record::RecordItem;
+use crate::util::disk_usage;
+use crate::Graveyard;
+
+/// Limits on the space taken by the graveyard
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Limits {
+    /// Most space the graves can take on disk
+    pub max_size: Option<u64>,
+    /// Least space to leave free on the filesystem of the graveyard
+    pub min_free: u64,
+}
+
+/// The limit that something would go over if it was buried
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    /// The graves would take more than `max_size`
+    MaxSize,
+    /// The filesystem of the graveyard would have less than `min_free` left
+    MinFree,
+}
+
+/// How far short the graveyard is of the room for something to be buried
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Shortfall {
+    pub limit: Limit,
+    /// Space that would be taken in the graveyard
+    pub needed: u64,
+    /// Space that is left before going over the limit
+    pub available: u64,
+    /// Space taken by the graves that are already buried
+    pub used: u64,
+    /// Space taken by the graves that are pinned, which can't be expired
+    pub pinned: u64,
+}
+
+impl Shortfall {
+    /// Space that has to be freed to make room
+    pub fn deficit(&self) -> u64 {
+        self.needed.saturating_sub(self.available)
+    }
+
+    /// Whether expiring graves could make enough room
+    pub fn reclaimable(&self) -> bool {
+        self.deficit() <= self.used - self.pinned
+    }
+}
+
+impl Graveyard {
+    /// Check that `source` can be buried without going over the limits of the
+    /// graveyard, returning how far short it is if it can't. Free space is
+    /// only needed when `source` has to be copied to another filesystem.
+    pub fn room_for<P: AsRef<Path>>(&self, source: P) -> Result<Option<Shortfall>> {
+        let source = source.as_ref();
+        let limits = self.limits;
+        let copied = !same_filesystem(source, self.path());
+        if limits.max_size.is_none() && !copied {
+            return Ok(None);
+        }
+
+        let needed = disk_usage(source);
+        // Space taken by every grave, and by the pinned ones
+        let usage = || -> Result<(u64, u64)> {
+            Ok(self.entries()?.iter().fold((0, 0), |(used, pinned), entry| {
+                let size = self.grave_size(&entry.dest);
+                (used + size, if entry.pinned { pinned + size } else { pinned })
+            }))
+        };
+
+        if let Some(max_size) = limits.max_size {
+            let (used, pinned) = usage()?;
+            if used + needed > max_size {
+                return Ok(Some(Shortfall {
+                    limit: Limit::MaxSize,
+                    needed,
+                    available: max_size.saturating_sub(used),
+                    used,
+                    pinned,
+                }));
+            }
+        }
+        if copied {
+            let free = free_space(&existing_ancestor(self.path()))
+                .chain_err(|| "Couldn't tell how much space is free for the graveyard")?;
+            if needed + limits.min_free > free {
+                let (used, pinned) = usage()?;
+                return Ok(Some(Shortfall {
+                    limit: Limit::MinFree,
+                    needed,
+                    available: free.saturating_sub(limits.min_free),
+                    used,
+                    pinned,
+                }));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Space taken on disk by `grave`. Each grave is only measured once, until
+    /// it is changed by burying, unburying or purging.
+    pub(crate) fn grave_size(&self, grave: &Path) -> u64 {
+        let mut sizes = self.sizes.lock().unwrap_or_else(|e| e.into_inner());
+        *sizes.entry(grave.to_path_buf()).or_insert_with(|| disk_usage(grave))
+    }
+
+    /// Measure `path` again, along with the grave it is inside of
+    pub(crate) fn forget_size(&self, path: &Path) {
+        let mut sizes = self.sizes.lock().unwrap_or_else(|e| e.into_inner());
+        sizes.retain(|grave, _| !path.starts_with(grave));
+    }
+
+    /// Expire the oldest graves, other than `spare` and pinned graves, until
+    /// `shortfall` is made up. Returns the entries that were expired, along with the space that
+    /// was freed by each.
+    pub fn make_room(&self, shortfall: &Shortfall, spare: &[PathBuf]) -> Result<Vec<(RecordItem, u64)>> {
+        self.gc(&Retention {
+            max_size: Some(shortfall.used.saturating_sub(shortfall.deficit())),
+            spare: spare.to_vec(),
+            ..Retention::default()
+        })
+    }
+}
+
+/// Whether `source` can be renamed into the graveyard at `graveyard`, i.e.,
+/// whether they are on the same filesystem
+fn same_filesystem(source: &Path, graveyard: &Path) -> bool {
+    match (fs::symlink_metadata(source), fs::metadata(existing_ancestor(graveyard))) {
+        (Ok(source), Ok(graveyard)) => source.dev() == graveyard.dev(),
+        _ => false,
+    }
+}
+
+/// The graveyard, or the directory it will be made in if it doesn't exist yet
+fn existing_ancestor(path: &Path) -> PathBuf {
+    path.ancestors()
+        .find(|dir| dir.exists())
+        .unwrap_or_else(|| Path::new("/"))
+        .to_path_buf()
+}
+
+/// Space on the filesystem of `path` that is free for unprivileged users
+fn free_space(path: &Path) -> io::Result<u64> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let mut stat = MaybeUninit::<libc::statvfs>::uninit();
+    if unsafe { libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let stat = unsafe { stat.assume_init() };
+    // The types of these fields differ between platforms
+    #[allow(clippy::unnecessary_cast)]
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
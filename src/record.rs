@@ -0,0 +1,481 @@
+//! The record is a log of every burial kept at the top of the graveyard.
+//!
+//! It is stored as JSON Lines: a header carrying the format version, followed
+//! by one [`RecordItem`] per line. Records written by older versions of `rip`
+//! (three tab separated columns, with no header) can still be read, and are
+//! migrated to the current format the first time the record is written to.
+//!
+//! Paths are stored as JSON strings when they are valid UTF-8, and as arrays of
+//! bytes otherwise, so that any file name can be restored. The time of each
+//! burial is stored in RFC 3339 format.
+//!
+//! Changes to the record are made while holding the lock of the graveyard, and
+//! the record is rewritten by renaming a new file over it, so that it is never
+//! seen truncated.
+use chrono::{DateTime, FixedOffset, Local, NaiveDateTime, SecondsFormat, TimeZone};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::{
+    ffi::{OsStr, OsString},
+    fs,
+    io::{self, BufWriter, Read, Write},
+    os::unix::ffi::{OsStrExt, OsStringExt},
+    path::{Path, PathBuf},
+};
+
+use crate::lock::Lock;
+use crate::LOCK;
+
+/// Version of the record format written by this version of `rip`
+pub const RECORD_VERSION: u32 = 2;
+
+/// A single burial in the record
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecordItem {
+    /// When the file was sent to the graveyard
+    #[serde(alias = "time", with = "timestamp")]
+    pub deleted: DateTime<FixedOffset>,
+    #[serde(with = "path_bytes")]
+    pub orig: PathBuf,
+    #[serde(with = "path_bytes")]
+    pub dest: PathBuf,
+    /// Set when the grave is an empty file standing in for a socket, which
+    /// is turned back into a socket when it is unburied
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub placeholder: bool,
+    /// Checksum of the grave when it was buried, if asked for
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub digest: Option<String>,
+    /// Set when the grave is spared from being expired or decomposed unless
+    /// that is forced
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub pinned: bool,
+}
+
+/// First line of the record
+#[derive(Debug, Serialize, Deserialize)]
+struct Header {
+    version: u32,
+}
+
+/// A line of the record, which may not be understood
+#[derive(Debug, Clone)]
+enum Line {
+    Entry(RecordItem),
+    Malformed(Vec<u8>),
+}
+
+/// Contents of the record as it was found on disk
+struct Contents {
+    /// Whether the record has a header, i.e., does not need to be migrated
+    versioned: bool,
+    lines: Vec<Line>,
+}
+
+/// Handle to the record file of a graveyard
+#[derive(Debug, Clone)]
+pub struct Record {
+    path: PathBuf,
+    /// Lock of the graveyard the record is in
+    lock: PathBuf,
+}
+
+impl Record {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        let path = path.into();
+        let lock = path.with_file_name(LOCK);
+        Record { path, lock }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Return every entry in the record, oldest first. Lines that can't be
+    /// parsed are skipped. A missing record is treated as an empty one.
+    pub fn entries(&self) -> io::Result<Vec<RecordItem>> {
+        let _lock = Lock::shared(&self.lock)?;
+        Ok(self
+            .read()?
+            .lines
+            .into_iter()
+            .filter_map(|line| match line {
+                Line::Entry(entry) => Some(entry),
+                Line::Malformed(_) => None,
+            })
+            .collect())
+    }
+
+    /// Add `entry` to the end of the record, migrating a legacy record first
+    pub fn append(&self, entry: &RecordItem) -> io::Result<()> {
+        let _lock = Lock::exclusive(&self.lock)?;
+        let contents = self.read()?;
+        if !contents.versioned {
+            let mut lines = contents.lines;
+            lines.push(Line::Entry(entry.clone()));
+            return self.write(&lines);
+        }
+
+        let mut f = fs::OpenOptions::new().append(true).open(&self.path)?;
+        writeln!(f, "{}", serialize(entry)?)
+    }
+
+    /// Only keep the entries for which `keep` returns true, rewriting the record
+    pub fn retain<F>(&self, mut keep: F) -> io::Result<()>
+    where
+        F: FnMut(&RecordItem) -> bool,
+    {
+        let _lock = Lock::exclusive(&self.lock)?;
+        let lines = self
+            .read()?
+            .lines
+            .into_iter()
+            .filter(|line| match line {
+                Line::Entry(entry) => keep(entry),
+                Line::Malformed(_) => true,
+            })
+            .collect::<Vec<_>>();
+        self.write(&lines)
+    }
+
+    /// Pass each entry to `change`, rewriting the record
+    pub fn update<F>(&self, mut change: F) -> io::Result<()>
+    where
+        F: FnMut(&mut RecordItem),
+    {
+        let _lock = Lock::exclusive(&self.lock)?;
+        let mut lines = self.read()?.lines;
+        for line in &mut lines {
+            if let Line::Entry(entry) = line {
+                change(entry);
+            }
+        }
+        self.write(&lines)
+    }
+
+    /// Return the lines of the record that can't be parsed
+    pub fn malformed(&self) -> io::Result<Vec<Vec<u8>>> {
+        let _lock = Lock::shared(&self.lock)?;
+        Ok(self
+            .read()?
+            .lines
+            .into_iter()
+            .filter_map(|line| match line {
+                Line::Entry(_) => None,
+                Line::Malformed(raw) => Some(raw),
+            })
+            .collect())
+    }
+
+    /// Remove the lines of the record that can't be parsed
+    pub fn remove_malformed(&self) -> io::Result<()> {
+        let _lock = Lock::exclusive(&self.lock)?;
+        let lines = self
+            .read()?
+            .lines
+            .into_iter()
+            .filter(|line| matches!(line, Line::Entry(_)))
+            .collect::<Vec<_>>();
+        self.write(&lines)
+    }
+
+    /// Takes a vector of grave paths and removes the respective entries
+    pub fn remove(&self, graves: &[PathBuf]) -> io::Result<()> {
+        self.retain(|entry| !graves.contains(&entry.dest))
+    }
+
+    fn read(&self) -> io::Result<Contents> {
+        let mut contents = Vec::new();
+        match fs::File::open(&self.path) {
+            Ok(mut f) => {
+                f.read_to_end(&mut contents)?;
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {},
+            Err(e) => return Err(e),
+        }
+
+        let mut lines = contents
+            .split(|&b| b == b'\n')
+            .filter(|l| !l.is_empty())
+            .peekable();
+        let header = lines
+            .peek()
+            .and_then(|l| serde_json::from_slice::<Header>(l).ok());
+        if let Some(header) = &header {
+            if header.version > RECORD_VERSION {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "{} was written by a newer version of rip (format version {})",
+                        self.path.display(),
+                        header.version
+                    ),
+                ));
+            }
+            lines.next();
+        }
+
+        Ok(Contents {
+            versioned: header.is_some(),
+            lines: lines.map(parse_line).collect(),
+        })
+    }
+
+    /// Replace the record with a header followed by `lines`. Only called while
+    /// holding the lock, so the temporary file can't be written by anyone else.
+    fn write(&self, lines: &[Line]) -> io::Result<()> {
+        let mut tmp = self.path.as_os_str().to_os_string();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+
+        let mut f = BufWriter::new(fs::File::create(&tmp)?);
+        writeln!(f, "{}", serialize(&Header { version: RECORD_VERSION })?)?;
+        for line in lines {
+            match line {
+                Line::Entry(entry) => writeln!(f, "{}", serialize(entry)?)?,
+                Line::Malformed(raw) => {
+                    f.write_all(raw)?;
+                    f.write_all(b"\n")?;
+                },
+            }
+        }
+        f.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        fs::rename(&tmp, &self.path)
+    }
+}
+
+fn serialize<T: Serialize>(value: &T) -> io::Result<String> {
+    serde_json::to_string(value).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Parse a line in the record, in either the current or the legacy format
+fn parse_line(line: &[u8]) -> Line {
+    if line.starts_with(b"{") {
+        if let Ok(entry) = serde_json::from_slice(line) {
+            return Line::Entry(entry);
+        }
+    } else if let Some(entry) = parse_legacy(line) {
+        return Line::Entry(entry);
+    }
+    Line::Malformed(line.to_vec())
+}
+
+/// Parse a line written by older versions of `rip`: `time\torig\tdest`
+fn parse_legacy(line: &[u8]) -> Option<RecordItem> {
+    let mut tokens = line.split(|&b| b == b'\t');
+    let entry = RecordItem {
+        deleted: timestamp::parse(std::str::from_utf8(tokens.next()?).ok()?)?,
+        orig: PathBuf::from(OsStr::from_bytes(tokens.next()?)),
+        dest: PathBuf::from(OsStr::from_bytes(tokens.next()?)),
+        placeholder: false,
+        digest: None,
+        pinned: false,
+    };
+    if tokens.next().is_some() {
+        return None;
+    }
+    Some(entry)
+}
+
+/// (De)serialize a path losslessly: as a string when it is valid UTF-8, and as
+/// its raw bytes otherwise
+pub(crate) mod path_bytes {
+    use super::*;
+
+    #[derive(Serialize)]
+    #[serde(untagged)]
+    enum Encoded<'a> {
+        Utf8(&'a str),
+        Bytes(&'a [u8]),
+    }
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Decoded {
+        Utf8(String),
+        Bytes(Vec<u8>),
+    }
+
+    pub fn serialize<S: Serializer>(path: &Path, serializer: S) -> Result<S::Ok, S::Error> {
+        match path.to_str() {
+            Some(s) => Encoded::Utf8(s),
+            None => Encoded::Bytes(path.as_os_str().as_bytes()),
+        }
+        .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<PathBuf, D::Error> {
+        Ok(match Decoded::deserialize(deserializer)? {
+            Decoded::Utf8(s) => PathBuf::from(s),
+            Decoded::Bytes(b) => PathBuf::from(OsString::from_vec(b)),
+        })
+    }
+}
+
+/// (De)serialize the time of a burial as RFC 3339. Older records used the
+/// format of `ctime(3)` in local time, which is still accepted.
+mod timestamp {
+    use super::*;
+    use serde::de::Error;
+
+    const CTIME: &str = "%a %b %e %H:%M:%S %Y";
+
+    pub fn parse(s: &str) -> Option<DateTime<FixedOffset>> {
+        DateTime::parse_from_rfc3339(s).ok().or_else(|| {
+            let naive = NaiveDateTime::parse_from_str(s.trim(), CTIME).ok()?;
+            Local
+                .from_local_datetime(&naive)
+                .earliest()
+                .map(|local| local.with_timezone(local.offset()))
+        })
+    }
+
+    pub fn serialize<S: Serializer>(
+        time: &DateTime<FixedOffset>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&time.to_rfc3339_opts(SecondsFormat::Secs, false))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<DateTime<FixedOffset>, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        parse(&s).ok_or_else(|| D::Error::custom(format!("invalid timestamp: {}", s)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+
+    /// A record in a directory of its own, which is removed when dropped
+    struct Scratch {
+        dir: PathBuf,
+        record: Record,
+    }
+
+    impl Scratch {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("rip-record-{}-{}", name, process::id()));
+            fs::remove_dir_all(&dir).ok();
+            fs::create_dir_all(&dir).unwrap();
+            let record = Record::new(dir.join(crate::RECORD));
+            Scratch { dir, record }
+        }
+
+        fn write(&self, contents: &[u8]) {
+            fs::write(self.record.path(), contents).unwrap();
+        }
+
+        fn contents(&self) -> Vec<u8> {
+            fs::read(self.record.path()).unwrap()
+        }
+
+        fn lines(&self) -> Vec<Vec<u8>> {
+            self.contents()
+                .split(|&b| b == b'\n')
+                .filter(|l| !l.is_empty())
+                .map(<[u8]>::to_vec)
+                .collect()
+        }
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            fs::remove_dir_all(&self.dir).ok();
+        }
+    }
+
+    fn item(orig: &[u8], dest: &[u8]) -> RecordItem {
+        RecordItem {
+            deleted: DateTime::parse_from_rfc3339("2024-01-02T03:04:05+01:00").unwrap(),
+            orig: PathBuf::from(OsStr::from_bytes(orig)),
+            dest: PathBuf::from(OsStr::from_bytes(dest)),
+            placeholder: false,
+            digest: None,
+            pinned: false,
+        }
+    }
+
+    #[test]
+    fn reads_legacy_lines_with_ctime_dates() {
+        let scratch = Scratch::new("legacy");
+        scratch.write(b"Sat Jul 31 16:40:45 2021\t/home/jack/file1\t/tmp/graveyard/home/jack/file1\n");
+
+        let entries = scratch.record.entries().unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].orig, Path::new("/home/jack/file1"));
+        assert_eq!(entries[0].dest, Path::new("/tmp/graveyard/home/jack/file1"));
+        assert_eq!(
+            entries[0].deleted.naive_local(),
+            NaiveDateTime::parse_from_str("2021-07-31 16:40:45", "%Y-%m-%d %H:%M:%S").unwrap()
+        );
+    }
+
+    #[test]
+    fn round_trips_non_utf8_paths_as_bytes() {
+        let scratch = Scratch::new("bytes");
+        let entry = item(b"/home/jack/caf\xe9", b"/tmp/graveyard/home/jack/caf\xe9");
+        scratch.record.append(&entry).unwrap();
+
+        let lines = scratch.lines();
+        assert_eq!(lines[0], br#"{"version":2}"#);
+        let line = String::from_utf8(lines[1].clone()).unwrap();
+        assert!(line.contains(r#""orig":[47,104,111,109,101"#), "{}", line);
+        assert_eq!(scratch.record.entries().unwrap(), vec![entry]);
+    }
+
+    #[test]
+    fn keeps_malformed_lines_on_rewrite() {
+        let scratch = Scratch::new("malformed");
+        let kept = item(b"/a", b"/tmp/graveyard/a");
+        let removed = item(b"/b", b"/tmp/graveyard/b");
+        scratch.write(
+            format!(
+                "{{\"version\":2}}\n{}\nnot a line of the record\n{}\n",
+                serialize(&kept).unwrap(),
+                serialize(&removed).unwrap()
+            )
+            .as_bytes(),
+        );
+
+        scratch.record.remove(std::slice::from_ref(&removed.dest)).unwrap();
+
+        let lines = scratch.lines();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[2], b"not a line of the record");
+        assert_eq!(scratch.record.entries().unwrap(), vec![kept]);
+        assert_eq!(scratch.record.malformed().unwrap(), vec![b"not a line of the record".to_vec()]);
+    }
+
+    #[test]
+    fn migrates_legacy_record_on_append() {
+        let scratch = Scratch::new("migrate");
+        scratch.write(b"Sat Jul 31 16:40:45 2021\t/a\t/tmp/graveyard/a\n");
+        let entry = item(b"/b", b"/tmp/graveyard/b");
+
+        scratch.record.append(&entry).unwrap();
+
+        let lines = scratch.lines();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], br#"{"version":2}"#);
+        assert!(lines[1..].iter().all(|line| line.starts_with(b"{")));
+        let entries = scratch.record.entries().unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].orig, Path::new("/a"));
+        assert_eq!(entries[1], entry);
+    }
+
+    #[test]
+    fn rejects_newer_versions() {
+        let scratch = Scratch::new("newer");
+        let contents = format!("{{\"version\":{}}}\n", RECORD_VERSION + 1);
+        scratch.write(contents.as_bytes());
+
+        let e = scratch.record.entries().unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert!(scratch.record.append(&item(b"/a", b"/tmp/graveyard/a")).is_err());
+        assert_eq!(scratch.contents(), contents.as_bytes());
+    }
+}
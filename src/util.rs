@@ -1,5 +1,20 @@
+use chrono::{Duration, Local};
+use colored::*;
+use std::{
+    collections::HashSet,
+    env,
+    ffi::OsStr,
+    fs, iter,
+    io::{self, Read, Write},
+    os::unix::fs::{FileTypeExt, MetadataExt},
+    path::{Path, PathBuf},
+};
+use walkdir::WalkDir;
+
+use crate::interrupt;
+
 /// Concatenate two paths, even if the right argument is an absolute path.
-fn join_absolute<A: AsRef<Path>, B: AsRef<Path>>(left: A, right: B) -> PathBuf {
+pub fn join_absolute<A: AsRef<Path>, B: AsRef<Path>>(left: A, right: B) -> PathBuf {
     let (left, right) = (left.as_ref(), right.as_ref());
     left.join(if let Ok(stripped) = right.strip_prefix("/") {
         stripped
@@ -8,16 +23,17 @@ fn join_absolute<A: AsRef<Path>, B: AsRef<Path>>(left: A, right: B) -> PathBuf {
     })
 }
 
-fn symlink_exists<P: AsRef<Path>>(path: P) -> bool {
+pub fn symlink_exists<P: AsRef<Path>>(path: P) -> bool {
     fs::symlink_metadata(path).is_ok()
 }
 
-fn get_user() -> String {
+pub fn get_user() -> String {
     env::var("USER").unwrap_or_else(|_| String::from("unknown"))
 }
 
-/// Prompt for user input, returning True if the first character is 'y' or 'Y'
-fn prompt_yes<T: AsRef<str>>(prompt: T) -> bool {
+/// Prompt for user input, returning True if the first character is 'y' or 'Y'.
+/// Ctrl-C while waiting is the same as answering no.
+pub fn prompt_yes<T: AsRef<str>>(prompt: T) -> bool {
     print!("{} [{}/{}] ", prompt.as_ref(),
         "y".green().bold(),
         "N".red().bold()
@@ -30,27 +46,127 @@ fn prompt_yes<T: AsRef<str>>(prompt: T) -> bool {
             "N".red().bold()
         );
     }
-    let stdin = BufReader::new(io::stdin());
-    stdin
-        .bytes()
-        .next()
-        .and_then(|c| c.ok())
-        .map(|c| c as char)
-        .map(|c| (c == 'y' || c == 'Y'))
+    read_answer()
+        .and_then(|line| line.chars().next())
+        .map(|c| c == 'y' || c == 'Y')
         .unwrap_or(false)
 }
 
+/// Prompt for one of `choices`, such as "gdA", returning the one that was
+/// picked in lowercase. The uppercase choice is the default, and is what
+/// Ctrl-C while waiting picks, so it should be the one that aborts.
+pub fn prompt_choice<T: AsRef<str>>(prompt: T, choices: &str) -> char {
+    let shown = choices
+        .chars()
+        .map(|c| if c.is_uppercase() {
+            c.to_string().red().bold().to_string()
+        } else {
+            c.to_string().green().bold().to_string()
+        })
+        .collect::<Vec<_>>()
+        .join("/");
+    print!("{} [{}] ", prompt.as_ref(), shown);
+    if io::stdout().flush().is_err() {
+        println!("{} [{}]", prompt.as_ref(), shown);
+    }
+    let default = choices.chars().find(|c| c.is_uppercase()).unwrap_or_default();
+    read_answer()
+        .and_then(|line| line.trim().chars().next())
+        .filter(|c| choices.to_lowercase().contains(c.to_ascii_lowercase()))
+        .unwrap_or(default)
+        .to_ascii_lowercase()
+}
+
+/// Read a line of stdin, or `None` if it can't be read or `SIGINT` or `SIGTERM`
+/// is caught while waiting. Reading a line with std would retry on `EINTR`,
+/// and so keep waiting after the signal was caught.
+fn read_answer() -> Option<String> {
+    let stdin = io::stdin();
+    let mut stdin = stdin.lock();
+    let mut line = Vec::new();
+    let mut byte = [0];
+    loop {
+        match stdin.read(&mut byte) {
+            Ok(0) => break,
+            Ok(_) if byte[0] == b'\n' => break,
+            Ok(_) => line.push(byte[0]),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted && !interrupt::requested() => {},
+            Err(_) => break,
+        }
+    }
+    if interrupt::requested() {
+        println!();
+        return None;
+    }
+    Some(String::from_utf8_lossy(&line).into_owned())
+}
+
+/// An entry found by [`walk`]
+#[derive(Debug)]
+pub struct Entry {
+    pub path: PathBuf,
+    /// How far below the path that was walked this is
+    pub depth: usize,
+    /// The entry's own metadata, not its target's if it is a symlink
+    pub metadata: fs::Metadata,
+}
+
+impl Entry {
+    pub fn file_name(&self) -> &OsStr {
+        self.path.file_name().unwrap_or_else(|| self.path.as_os_str())
+    }
+}
+
+/// `path` and everything under it, sorted by name within each directory.
+/// `WalkDir` follows a symlink that it is given as the root, so only a
+/// directory is walked, and anything else is taken as it is.
+pub fn walk<P: AsRef<Path>>(path: P) -> impl Iterator<Item = io::Result<Entry>> {
+    let path = path.as_ref().to_path_buf();
+    let root = fs::symlink_metadata(&path);
+    let below = match root {
+        Ok(ref metadata) if metadata.is_dir() => Some(
+            WalkDir::new(&path)
+                .min_depth(1)
+                .sort_by(|a, b| a.cmp(b))
+                .into_iter()
+                .map(|entry| {
+                    let entry = entry.map_err(io::Error::other)?;
+                    Ok(Entry {
+                        metadata: entry.metadata().map_err(io::Error::other)?,
+                        path: entry.path().to_path_buf(),
+                        depth: entry.depth(),
+                    })
+                }),
+        ),
+        _ => None,
+    };
+    iter::once(root.map(|metadata| Entry { path, depth: 0, metadata }))
+        .chain(below.into_iter().flatten())
+}
+
 /// Add a numbered extension to duplicate filenames to avoid overwriting files.
-fn rename_grave<G: AsRef<Path>>(grave: G) -> PathBuf {
+pub fn rename_grave<G: AsRef<Path>>(grave: G) -> PathBuf {
+    rename_grave_with(grave, |p| symlink_exists(p))
+}
+
+/// Add the first numbered extension to `grave` for which `taken` is false
+pub fn rename_grave_with<G, F>(grave: G, taken: F) -> PathBuf
+where
+    G: AsRef<Path>,
+    F: Fn(&Path) -> bool,
+{
     let grave = grave.as_ref();
-    let name = grave.to_str().expect("Filename must be valid unicode.");
     (1_u64..)
-        .map(|i| PathBuf::from(format!("{}~{}", name, i)))
-        .find(|p| !symlink_exists(p))
+        .map(|i| {
+            let mut name = grave.as_os_str().to_os_string();
+            name.push(format!("~{}", i));
+            PathBuf::from(name)
+        })
+        .find(|p| !taken(p))
         .expect("Failed to rename duplicate file or directory")
 }
 
-fn humanize_bytes(bytes: u64) -> String {
+pub fn humanize_bytes(bytes: u64) -> String {
     let values = ["bytes", "KB", "MB", "GB", "TB"];
     let pair = values
         .iter()
@@ -67,3 +183,92 @@ fn humanize_bytes(bytes: u64) -> String {
         format!("{} {}", bytes, values[0])
     }
 }
+
+/// Space taken on disk by `path` and everything under it, counting files with
+/// several links inside of it once
+pub fn disk_usage<P: AsRef<Path>>(path: P) -> u64 {
+    usage(path).bytes
+}
+
+/// What is under a path, as counted by [`usage`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Usage {
+    /// Space taken on disk
+    pub bytes: u64,
+    /// Number of entries that aren't directories
+    pub files: u64,
+}
+
+/// Space taken on disk by `path` and everything under it, along with the number
+/// of files there are. Files with several links inside of it are counted once.
+pub fn usage<P: AsRef<Path>>(path: P) -> Usage {
+    let mut inodes = HashSet::new();
+    walk(path)
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.metadata)
+        .filter(|md| md.nlink() < 2 || md.is_dir() || inodes.insert((md.dev(), md.ino())))
+        .fold(Usage::default(), |usage, md| Usage {
+            bytes: usage.bytes + md.blocks() * 512,
+            files: usage.files + u64::from(!md.is_dir()),
+        })
+}
+
+/// Parse a duration such as `90m`, `12h`, `30d` or `2w`
+pub fn parse_duration(s: &str) -> Option<Duration> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let n = s[..split].parse::<i64>().ok()?;
+    let unit = match s[split..].trim() {
+        "s" => 1000,
+        "m" => 60 * 1000,
+        "h" => 60 * 60 * 1000,
+        "d" => 24 * 60 * 60 * 1000,
+        "w" => 7 * 24 * 60 * 60 * 1000,
+        _ => return None,
+    };
+    // Unlike the others, this can't panic when it is too large
+    Some(Duration::milliseconds(n.checked_mul(unit)?))
+}
+
+/// The time `age` ago as a Unix timestamp, or `None` if that is further back
+/// than a date can go
+pub fn cutoff(age: Duration) -> Option<i64> {
+    Local::now().checked_sub_signed(age).map(|time| time.timestamp())
+}
+
+/// Parse a size in bytes such as `500M` or `10G`. Units are powers of 1000,
+/// like the ones `humanize_bytes` prints, unless given as `KiB`, `MiB`, etc.
+pub fn parse_size(s: &str) -> Option<u64> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(s.len());
+    let n = s[..split].parse::<f64>().ok()?;
+    let unit: u64 = match s[split..].trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" => 1000,
+        "m" | "mb" => 1000_u64.pow(2),
+        "g" | "gb" => 1000_u64.pow(3),
+        "t" | "tb" => 1000_u64.pow(4),
+        "kib" => 1 << 10,
+        "mib" => 1 << 20,
+        "gib" => 1 << 30,
+        "tib" => 1 << 40,
+        _ => return None,
+    };
+    Some((n * unit as f64) as u64)
+}
+
+/// Get the file's file type for displaying it
+pub fn file_type(p: &Path) -> String {
+    let ft = match fs::symlink_metadata(p) {
+        Ok(metadata) => metadata.file_type(),
+        Err(_) => return String::from("N/A"),
+    };
+    if ft.is_file() { String::from("file") }
+    else if ft.is_dir() { String::from("dir") }
+    else if ft.is_symlink() { String::from("link") }
+    else if ft.is_fifo() { String::from("fifo") }
+    else if ft.is_socket() { String::from("sock") }
+    else if ft.is_char_device() { String::from("char") }
+    else if ft.is_block_device() { String::from("block") }
+    else { String::from("other") }
+}